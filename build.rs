@@ -0,0 +1,94 @@
+//! Build time checks that kernel.ld and src/hw/param.rs agree.
+// The linker script can't see Rust constants and rustc can't see linker
+// symbols, so compare the two sources as text. Anything we can't find
+// is a warning rather than an error, the layout may just have moved.
+use std::fs;
+
+const LINKER_SCRIPT: &str = "kernel.ld";
+const PARAMS: &str = "src/hw/param.rs";
+
+fn warn(msg: &str) {
+    println!("cargo:warning={}", msg);
+}
+
+fn parse_int(s: &str) -> Option<usize> {
+    let s = s.trim().trim_end_matches([';', ',']).trim();
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => s.replace('_', "").parse().ok(),
+    }
+}
+
+// Product of a parenthesized expression like `(4096 * 3 * 2)`.
+fn eval_product(expr: &str) -> Option<usize> {
+    expr.trim()
+        .trim_start_matches('(')
+        .trim_end_matches([')', ';'])
+        .split('*')
+        .map(parse_int)
+        .product()
+}
+
+/// Value of `pub const NAME: type = value;` in param.rs.
+fn param_const(params: &str, name: &str) -> Option<usize> {
+    let line = params
+        .lines()
+        .find(|l| l.trim_start().starts_with(&format!("pub const {}:", name)))?;
+    let value = line.split('=').nth(1)?.split(';').next()?;
+    // DRAM_BASE is written `0x80000000 as *mut usize`.
+    parse_int(value.split(" as ").next()?)
+}
+
+/// ORIGIN of the RAM region in the MEMORY block.
+fn ram_origin(script: &str) -> Option<usize> {
+    let line = script.lines().find(|l| l.trim_start().starts_with("RAM"))?;
+    let origin = line.split("ORIGIN").nth(1)?.split('=').nth(1)?;
+    parse_int(origin.split(',').next()?)
+}
+
+/// Size reserved by `. = . + (...)` in the .stacks output section.
+fn stacks_size(script: &str) -> Option<usize> {
+    let section = script.split(".stacks").nth(1)?.split('}').next()?;
+    let line = section.lines().find(|l| l.contains(". = . +"))?;
+    let expr = line.split(". = . +").nth(1)?.split("/*").next()?;
+    eval_product(expr)
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", LINKER_SCRIPT);
+    println!("cargo:rerun-if-changed={}", PARAMS);
+
+    let (script, params) = match (
+        fs::read_to_string(LINKER_SCRIPT),
+        fs::read_to_string(PARAMS),
+    ) {
+        (Ok(script), Ok(params)) => (script, params),
+        _ => {
+            warn("Couldn't read kernel.ld or param.rs, layout not checked");
+            return;
+        }
+    };
+
+    match (ram_origin(&script), param_const(&params, "DRAM_BASE")) {
+        (Some(origin), Some(base)) => assert_eq!(
+            origin, base,
+            "kernel.ld RAM ORIGIN {:#x} != param.rs DRAM_BASE {:#x}",
+            origin, base
+        ),
+        _ => warn("Couldn't compare kernel.ld RAM ORIGIN with DRAM_BASE"),
+    }
+
+    match (
+        stacks_size(&script),
+        param_const(&params, "NHART"),
+        param_const(&params, "STACK_SIZE"),
+    ) {
+        (Some(size), Some(nhart), Some(stack)) => assert!(
+            nhart * stack <= size,
+            "kernel.ld .stacks is {:#x} bytes, NHART * STACK_SIZE needs {:#x}",
+            size,
+            nhart * stack
+        ),
+        _ => warn("Couldn't check kernel.ld .stacks against NHART * STACK_SIZE"),
+    }
+}
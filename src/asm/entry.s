@@ -19,6 +19,9 @@ _entry:
     .extern _global_pointer
     la gp, _global_pointer
     .option pop
+    # Keep the device tree pointer from the firmware for _start
+    mv s1, a1
+
     # Set up stack per of hart ids according to linker script
 
     # Add 4k guard page per hart
@@ -41,8 +44,10 @@ _entry:
     sub a2, a2, a0 # Move sp down by scratch pad page + guard page
     csrw sscratch, a2 # Write per hart sscratch pad
 
-    # Jump to _start in src/main.rs
+    # Jump to _start(hartid, dtb) in src/main.rs
     .extern _start
+    csrr a0, mhartid
+    mv a1, s1
     call _start
 spin:
     wfi
@@ -32,6 +32,7 @@
     sd x28, 224(sp)
     sd x29, 232(sp)
     sd x30, 240(sp)
+    sd x31, 248(sp)
 .endm
 
 .macro load_gp_regs
@@ -65,6 +66,7 @@
     ld x28, 224(sp)
     ld x29, 232(sp)
     ld x30, 240(sp)
+    ld x31, 248(sp)
 
     addi sp, sp, 256
 .endm
@@ -79,6 +81,11 @@ __mtrapvec:
     csrrw sp, mscratch, sp
     save_gp_regs
 
+    # Check the interrupted kernel sp, now held in mscratch.
+    .extern check_kstack
+    csrr a0, mscratch
+    call check_kstack
+
     .extern m_handler
     call m_handler
 
@@ -95,7 +102,12 @@ __mtrapvec:
     csrrw sp, sscratch, sp
     save_gp_regs
 
+    # Check the interrupted kernel sp, now held in sscratch.
+    csrr a0, sscratch
+    call check_kstack
+
     .extern s_handler
+    mv a0, sp # Saved registers, see trap::TrapRegs.
     call s_handler
 
     load_gp_regs
@@ -0,0 +1,4 @@
+//! Boot time checks.
+pub mod bootinfo;
+pub mod fdt;
+pub mod memtest;
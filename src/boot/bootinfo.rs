@@ -0,0 +1,52 @@
+//! What the boot stub found out, handed to `kmain`.
+// _start fills in its hart's entry in machine mode, then mrets to main,
+// which just passes it on. It can't live on _start's stack, main reuses
+// that stack from where _start left off.
+use crate::hw::param::{bss_end, dram_end, NHART};
+
+#[derive(Copy, Clone)]
+pub struct BootInfo {
+    pub hart_id: usize,
+    /// Device tree blob from the firmware (a1 at entry), may be null.
+    pub dtb_ptr: *const u8,
+    /// Physical memory free for the kernel to use, [start, end).
+    pub memory_start: usize,
+    pub memory_end: usize,
+    /// The first user program, if the loader passed one in.
+    pub init_binary: *const u8,
+    pub init_len: usize,
+}
+
+impl BootInfo {
+    const EMPTY: BootInfo = BootInfo {
+        hart_id: 0,
+        dtb_ptr: core::ptr::null(),
+        memory_start: 0,
+        memory_end: 0,
+        init_binary: core::ptr::null(),
+        init_len: 0,
+    };
+}
+
+static mut BOOT_INFO: [BootInfo; NHART] = [BootInfo::EMPTY; NHART];
+
+/// Record `hart_id`'s boot information. Called once per hart by _start.
+pub fn init(hart_id: usize, dtb_ptr: *const u8) {
+    let info = BootInfo {
+        hart_id,
+        dtb_ptr,
+        memory_start: bss_end().addr(),
+        memory_end: dram_end().addr(),
+        // No loader hands us an init program yet.
+        init_binary: core::ptr::null(),
+        init_len: 0,
+    };
+    unsafe {
+        BOOT_INFO[hart_id] = info;
+    }
+}
+
+/// Boot information for `hart_id`, once _start has recorded it.
+pub fn get(hart_id: usize) -> &'static BootInfo {
+    unsafe { &*core::ptr::addr_of!(BOOT_INFO[hart_id]) }
+}
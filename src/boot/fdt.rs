@@ -0,0 +1,212 @@
+//! Minimal flattened device tree (DTB) reader.
+// Reference: Devicetree Specification v0.4, chapter 5 (Flattened
+// Devicetree Format). Just enough to walk the structure block and read a
+// few properties; no phandles, no aliases, no writing. Every field is
+// big-endian.
+//
+// The firmware's DTB sits in DRAM that the page pool later takes over, so
+// read what you need from it before vm::init.
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// A device tree blob in memory.
+#[derive(Copy, Clone)]
+pub struct Fdt {
+    base: *const u8,
+    structs: usize, // Offset of the structure block.
+    strings: usize, // Offset of the strings block.
+    size: usize,    // totalsize from the header.
+}
+
+/// One step of a walk over the structure block.
+pub enum Token<'a> {
+    /// Start of a node, with its name (`memory@80000000`, "" for the root).
+    BeginNode(&'a str),
+    EndNode,
+    /// A property of the innermost open node: name and raw value.
+    Prop(&'a str, &'a [u8]),
+}
+
+/// A `device_type = "memory"` node.
+#[derive(Copy, Clone)]
+pub struct MemoryNode {
+    /// First (address, size) pair of its `reg`.
+    pub base: usize,
+    pub size: usize,
+    pub numa_node_id: Option<u32>,
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Read a number `cells` 32-bit cells long from the start of `bytes`.
+fn read_cells(bytes: &[u8], cells: usize) -> Option<usize> {
+    let bytes = bytes.get(..cells * 4)?;
+    Some(
+        bytes
+            .chunks(4)
+            .fold(0, |acc, cell| (acc << 32) | be32(cell) as usize),
+    )
+}
+
+impl Fdt {
+    /// The blob at `ptr`, if there is one with a valid header.
+    /// # Safety
+    /// A non-null `ptr` must point at readable memory, and if that holds
+    /// a DTB header all `totalsize` bytes of it must be readable.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Option<Fdt> {
+        if ptr.is_null() || ptr.addr() & 3 != 0 {
+            return None;
+        }
+        let header = core::slice::from_raw_parts(ptr, 40);
+        if be32(&header[0..]) != FDT_MAGIC {
+            return None;
+        }
+        Some(Fdt {
+            base: ptr,
+            size: be32(&header[4..]) as usize,
+            structs: be32(&header[8..]) as usize,
+            strings: be32(&header[12..]) as usize,
+        })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.base, self.size) }
+    }
+
+    // The NUL terminated string at `off` in the blob.
+    fn str_at(&self, off: usize) -> Option<&str> {
+        let bytes = self.bytes().get(off..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+
+    /// Walk the structure block in order. Stops at FDT_END, or at the
+    /// first thing that doesn't parse.
+    pub fn tokens(&self) -> Tokens<'_> {
+        Tokens {
+            fdt: self,
+            off: self.structs,
+        }
+    }
+
+    /// `/cpus/timebase-frequency`, the mtime frequency in Hz.
+    pub fn timebase_frequency(&self) -> Option<u32> {
+        let mut depth = 0;
+        let mut in_cpus = false;
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    in_cpus = depth == 2 && name == "cpus";
+                }
+                Token::EndNode => {
+                    depth -= 1;
+                    in_cpus = false;
+                }
+                Token::Prop("timebase-frequency", value) if in_cpus && value.len() == 4 => {
+                    return Some(be32(value));
+                }
+                Token::Prop(..) => {}
+            }
+        }
+        None
+    }
+
+    /// Call `f` with every memory node directly under the root.
+    pub fn for_each_memory(&self, mut f: impl FnMut(MemoryNode)) {
+        // The root's #address-cells and #size-cells, spec defaults.
+        let (mut addr_cells, mut size_cells) = (2, 1);
+        let mut depth = 0;
+        // In a depth 2 node: is it memory, its reg, its numa-node-id.
+        let mut memory = false;
+        let mut reg: Option<&[u8]> = None;
+        let mut numa_node_id = None;
+        for token in self.tokens() {
+            match token {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    if depth == 2 {
+                        memory = name == "memory" || name.starts_with("memory@");
+                        reg = None;
+                        numa_node_id = None;
+                    }
+                }
+                Token::EndNode => {
+                    if depth == 2 && memory {
+                        let found = reg.and_then(|reg| {
+                            let base = read_cells(reg, addr_cells)?;
+                            let size = read_cells(reg.get(addr_cells * 4..)?, size_cells)?;
+                            Some((base, size))
+                        });
+                        if let Some((base, size)) = found {
+                            f(MemoryNode {
+                                base,
+                                size,
+                                numa_node_id,
+                            });
+                        }
+                    }
+                    depth -= 1;
+                }
+                Token::Prop(name, value) if depth == 1 => match name {
+                    "#address-cells" if value.len() == 4 => addr_cells = be32(value) as usize,
+                    "#size-cells" if value.len() == 4 => size_cells = be32(value) as usize,
+                    _ => {}
+                },
+                Token::Prop(name, value) if depth == 2 => match name {
+                    "device_type" => memory |= value == b"memory\0",
+                    "reg" => reg = Some(value),
+                    "numa-node-id" if value.len() == 4 => numa_node_id = Some(be32(value)),
+                    _ => {}
+                },
+                Token::Prop(..) => {}
+            }
+        }
+    }
+}
+
+/// Iterator over the tokens of a device tree's structure block.
+pub struct Tokens<'a> {
+    fdt: &'a Fdt,
+    off: usize, // Next token, always 4 byte aligned.
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let fdt = self.fdt;
+        let bytes = fdt.bytes();
+        loop {
+            let token = be32(bytes.get(self.off..self.off + 4)?);
+            self.off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = fdt.str_at(self.off)?;
+                    self.off = (self.off + name.len() + 1 + 3) & !3;
+                    return Some(Token::BeginNode(name));
+                }
+                FDT_END_NODE => return Some(Token::EndNode),
+                FDT_PROP => {
+                    let len = be32(bytes.get(self.off..self.off + 4)?) as usize;
+                    let nameoff = be32(bytes.get(self.off + 4..self.off + 8)?) as usize;
+                    let value = bytes.get(self.off + 8..self.off + 8 + len)?;
+                    let name = fdt.str_at(fdt.strings + nameoff)?;
+                    self.off = (self.off + 8 + len + 3) & !3;
+                    return Some(Token::Prop(name, value));
+                }
+                FDT_NOP => continue,
+                FDT_END => return None,
+                // Not a token, the blob is corrupt.
+                _ => return None,
+            }
+        }
+    }
+}
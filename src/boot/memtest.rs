@@ -0,0 +1,41 @@
+//! Physical memory self test.
+// Only free pages are tested: everything else in DRAM is either the
+// kernel image or already handed out. Enabled with the `memtest` feature.
+use crate::hw::param::PAGE_SIZE;
+use crate::vm::palloc::PagePool;
+
+const WORDS: usize = PAGE_SIZE / core::mem::size_of::<usize>();
+
+/// Write `pattern` to every word of the page and read it back.
+/// Returns true if every word matched.
+fn test_pattern(page: *mut usize, pattern: usize) -> bool {
+    let mut ok = true;
+    unsafe {
+        for i in 0..WORDS {
+            page.add(i).write_volatile(pattern);
+        }
+        for i in 0..WORDS {
+            ok &= page.add(i).read_volatile() == pattern;
+        }
+    }
+    ok
+}
+
+/// All ones, then a single one bit marching through every position.
+/// Leaves the page zeroed.
+fn test_page(page: *mut usize) -> bool {
+    let mut ok = test_pattern(page, usize::MAX);
+    for bit in 0..usize::BITS {
+        ok &= test_pattern(page, 1 << bit);
+    }
+    ok & test_pattern(page, 0)
+}
+
+/// Test every free page in pool, and take the bad ones out of the free
+/// list so they are never allocated. Returns the number of bad pages.
+pub fn memtest(pool: &PagePool) -> usize {
+    log!(Info, "Testing free physical memory...");
+    let bad = pool.retain_free(test_page);
+    log!(Info, "Memory test found {} bad pages...", bad);
+    bad
+}
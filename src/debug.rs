@@ -0,0 +1,8 @@
+//! Kernel debugging support.
+pub mod console_ring;
+#[cfg(feature = "gdbstub")]
+pub mod gdb;
+#[cfg(feature = "latency")]
+pub mod latency;
+pub mod syslog;
+pub mod trigger;
@@ -0,0 +1,64 @@
+//! Ring buffer of recent console output, for post-mortem debugging.
+// Everything printed goes to the UART and into CONSOLE_RING, which the
+// linker places at the `_console_ring` symbol at the start of .data so a
+// debugger can find and dump it after a crash.
+use core::fmt::{Error, Write};
+
+use crate::device::uart::{uart_early_putc, Uart};
+use crate::hw::riscv::InterruptGuard;
+use crate::lock::mutex::Mutex;
+
+pub const CONSOLE_RING_SIZE: usize = 8192;
+
+/// The last CONSOLE_RING_SIZE bytes written to the console.
+#[repr(C)]
+pub struct ConsoleRing {
+    buf: [u8; CONSOLE_RING_SIZE],
+    head: usize, // Next byte to write.
+    len: usize,  // Valid bytes, up to CONSOLE_RING_SIZE.
+}
+
+#[link_section = ".console_ring"]
+pub static CONSOLE_RING: Mutex<ConsoleRing> = Mutex::new(ConsoleRing {
+    buf: [0; CONSOLE_RING_SIZE],
+    head: 0,
+    len: 0,
+});
+
+impl ConsoleRing {
+    fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.buf[self.head] = b;
+            self.head = (self.head + 1) % CONSOLE_RING_SIZE;
+        }
+        self.len = core::cmp::min(self.len + bytes.len(), CONSOLE_RING_SIZE);
+    }
+
+    /// Write the saved output, oldest first, straight to the UART.
+    pub fn dump_to_uart(&self) {
+        let start = (self.head + CONSOLE_RING_SIZE - self.len) % CONSOLE_RING_SIZE;
+        for i in 0..self.len {
+            uart_early_putc(self.buf[(start + i) % CONSOLE_RING_SIZE]);
+        }
+    }
+}
+
+/// Dump CONSOLE_RING without taking its lock, since we may have
+/// panicked while holding it. For the panic handler.
+pub fn dump_console_ring() {
+    let ring = unsafe { &*CONSOLE_RING.as_ptr() };
+    ring.dump_to_uart();
+}
+
+/// The console: writes to the UART and records into CONSOLE_RING.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        Uart::new().lock().write_str(out)?;
+        // A trap that prints while we hold the ring would spin forever.
+        let _guard = InterruptGuard::new();
+        CONSOLE_RING.lock().push(out.as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+//! GDB remote serial protocol stub over the UART.
+// Reference: https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+// Entered from the supervisor trap handler on breakpoint exceptions, and
+// talks to GDB with the lock free early UART routines, so it works no
+// matter what the interrupted code was holding.
+//
+// Supports ?, g, G, m, M, c and qSupported. Single stepping needs the
+// debug trigger CSRs, which are machine mode only, so `s` is answered as
+// unsupported. Kernel text is mapped read only, so GDB can't place
+// software breakpoints there; compiled in ebreaks (see `breakpoint`)
+// still work.
+use core::arch::asm;
+
+use crate::device::uart::{uart_early_getc, uart_early_putc};
+use crate::hw::riscv;
+use crate::lock::mutex::Mutex;
+use crate::trap::TrapRegs;
+use crate::vm::ptable::PageTable;
+use crate::vm::types::VirtAddr;
+
+const BUF_SIZE: usize = 1024;
+// Tell GDB to leave room for the $, # and checksum.
+const PACKET_SIZE: &str = "PacketSize=3f0";
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+
+struct Buffers {
+    inp: [u8; BUF_SIZE],
+    out: [u8; BUF_SIZE],
+}
+
+// Not on the stack: the trap handler only has a page of scratch stack.
+static BUFFERS: Mutex<Buffers> = Mutex::new(Buffers {
+    inp: [0; BUF_SIZE],
+    out: [0; BUF_SIZE],
+});
+
+/// Stop in the stub, e.g. to wait for GDB to attach at boot.
+pub fn breakpoint() {
+    unsafe {
+        asm!("ebreak");
+    }
+}
+
+/// Reply being built up in the output buffer.
+struct Reply<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Reply<'a> {
+    fn push(&mut self, b: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = b;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for b in s.bytes() {
+            self.push(b);
+        }
+    }
+
+    fn push_hex(&mut self, b: u8) {
+        const DIGITS: &[u8] = b"0123456789abcdef";
+        self.push(DIGITS[(b >> 4) as usize]);
+        self.push(DIGITS[(b & 0xf) as usize]);
+    }
+
+    // Registers go over the wire in target (little endian) byte order.
+    fn push_reg(&mut self, val: usize) {
+        for b in val.to_le_bytes() {
+            self.push_hex(b);
+        }
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() {
+        return None;
+    }
+    s.iter()
+        .try_fold(0usize, |acc, &c| Some((acc << 4) | hex_digit(c)? as usize))
+}
+
+fn parse_byte(s: &[u8]) -> Option<u8> {
+    Some((hex_digit(*s.first()?)? << 4) | hex_digit(*s.get(1)?)?)
+}
+
+/// Parse "addr,len" and return the rest of the packet after it.
+fn parse_addr_len(s: &[u8]) -> Option<(usize, usize, &[u8])> {
+    let comma = s.iter().position(|&c| c == b',')?;
+    let end = s.iter().position(|&c| c == b':').unwrap_or(s.len());
+    let addr = parse_hex(&s[..comma])?;
+    let len = parse_hex(s.get(comma + 1..end)?)?;
+    Some((addr, len, s.get(end + 1..).unwrap_or(&[])))
+}
+
+/// Read one packet into buf, acking it. Returns the payload length.
+fn read_packet(buf: &mut [u8]) -> usize {
+    loop {
+        while uart_early_getc() != b'$' {}
+        let mut len = 0;
+        let mut sum: u8 = 0;
+        loop {
+            let c = uart_early_getc();
+            if c == b'#' {
+                break;
+            }
+            if len < buf.len() {
+                buf[len] = c;
+                len += 1;
+            }
+            sum = sum.wrapping_add(c);
+        }
+        let check = [uart_early_getc(), uart_early_getc()];
+        if parse_byte(&check) == Some(sum) {
+            uart_early_putc(b'+');
+            return len;
+        }
+        uart_early_putc(b'-');
+    }
+}
+
+/// Send one packet, resending until GDB acks it.
+fn write_packet(data: &[u8]) {
+    let sum = data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+    loop {
+        uart_early_putc(b'$');
+        for &b in data {
+            uart_early_putc(b);
+        }
+        let mut tail = Reply {
+            buf: &mut [0; 3],
+            len: 0,
+        };
+        tail.push(b'#');
+        tail.push_hex(sum);
+        for &b in tail.buf.iter() {
+            uart_early_putc(b);
+        }
+        if uart_early_getc() == b'+' {
+            return;
+        }
+    }
+}
+
+fn readable(addr: usize) -> bool {
+    match PageTable::current() {
+        Some(pt) => pt.virt_to_phys(VirtAddr::new(addr)).is_some(),
+        None => true,
+    }
+}
+
+fn writable(addr: usize) -> bool {
+    match PageTable::current() {
+        Some(pt) => pt.is_writable(VirtAddr::new(addr)),
+        None => true,
+    }
+}
+
+fn read_registers(regs: &TrapRegs, out: &mut Reply) {
+    out.push_reg(0);
+    for (i, &reg) in regs.iter().enumerate().skip(1) {
+        // The saved x2 is the scratch sp, not the one GDB wants.
+        out.push_reg(if i == 2 { riscv::read_sscratch() } else { reg });
+    }
+    out.push_reg(riscv::read_sepc());
+}
+
+fn write_registers(regs: &mut TrapRegs, data: &[u8]) -> bool {
+    let mut vals = data.chunks_exact(16).map(|reg| {
+        reg.chunks_exact(2)
+            .rev()
+            .try_fold(0usize, |acc, b| Some((acc << 8) | parse_byte(b)? as usize))
+    });
+    let mut new = [0; 33];
+    for slot in new.iter_mut() {
+        match vals.next().flatten() {
+            Some(val) => *slot = val,
+            None => return false,
+        }
+    }
+    // Keep the saved scratch sp, the trap exit reloads sp from it.
+    let scratch_sp = regs[2];
+    regs[1..].copy_from_slice(&new[1..32]);
+    regs[2] = scratch_sp;
+    riscv::write_sscratch(new[2]);
+    riscv::write_sepc(new[32]);
+    true
+}
+
+fn read_memory(addr: usize, len: usize, out: &mut Reply) {
+    if (addr..addr + len).any(|a| !readable(a)) {
+        out.push_str("E14");
+        return;
+    }
+    for a in addr..addr + len {
+        out.push_hex(unsafe { (a as *const u8).read_volatile() });
+    }
+}
+
+fn write_memory(addr: usize, len: usize, data: &[u8], out: &mut Reply) {
+    if data.len() < 2 * len || (addr..addr + len).any(|a| !writable(a)) {
+        out.push_str("E14");
+        return;
+    }
+    for (i, byte) in data.chunks_exact(2).take(len).enumerate() {
+        match parse_byte(byte) {
+            Some(b) => unsafe { ((addr + i) as *mut u8).write_volatile(b) },
+            None => {
+                out.push_str("E22");
+                return;
+            }
+        }
+    }
+    unsafe {
+        riscv::fence_i();
+    }
+    out.push_str("OK");
+}
+
+/// Step over an ebreak compiled into the kernel, so continuing doesn't
+/// trap straight back into the stub. GDB removes its own breakpoints
+/// before continuing, so any ebreak still at sepc is one of ours.
+fn skip_ebreak() {
+    let pc = riscv::read_sepc();
+    let half = unsafe { (pc as *const u16).read_volatile() };
+    if half == C_EBREAK {
+        riscv::write_sepc(pc + 2);
+    } else if half as u32 == EBREAK & 0xffff
+        && unsafe { (pc as *const u16).add(1).read_volatile() } as u32 == EBREAK >> 16
+    {
+        riscv::write_sepc(pc + 4);
+    }
+}
+
+/// Breakpoint trap: tell GDB we stopped and serve requests until it
+/// asks us to continue.
+pub fn handle_trap(regs: &mut TrapRegs) {
+    let mut bufs = BUFFERS.lock();
+    let Buffers { inp, out } = &mut *bufs;
+
+    write_packet(b"S05");
+    loop {
+        let len = read_packet(inp);
+        let packet = &inp[..len];
+        let mut reply = Reply {
+            buf: &mut out[..],
+            len: 0,
+        };
+
+        match packet.first() {
+            Some(b'?') => reply.push_str("S05"),
+            Some(b'g') => read_registers(regs, &mut reply),
+            Some(b'G') => match write_registers(regs, &packet[1..]) {
+                true => reply.push_str("OK"),
+                false => reply.push_str("E22"),
+            },
+            Some(b'm') => match parse_addr_len(&packet[1..]) {
+                Some((addr, len, _)) if 2 * len <= BUF_SIZE => read_memory(addr, len, &mut reply),
+                _ => reply.push_str("E22"),
+            },
+            Some(b'M') => match parse_addr_len(&packet[1..]) {
+                Some((addr, len, data)) => write_memory(addr, len, data, &mut reply),
+                None => reply.push_str("E22"),
+            },
+            Some(b'c') | Some(b'D') => {
+                if let Some(addr) = parse_hex(&packet[1..]) {
+                    riscv::write_sepc(addr);
+                }
+                if packet[0] == b'D' {
+                    write_packet(b"OK");
+                }
+                skip_ebreak();
+                return;
+            }
+            _ if packet.starts_with(b"qSupported") => reply.push_str(PACKET_SIZE),
+            // Anything else, single step included, is unsupported.
+            _ => {}
+        }
+        let len = reply.len;
+        write_packet(&out[..len]);
+    }
+}
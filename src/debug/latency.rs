@@ -0,0 +1,126 @@
+//! Interrupt latency measurement, using the CLINT mtime.
+// Tracks two things: how long supervisor interrupts stay disabled under
+// an InterruptGuard, per hart, and how long trap handlers take, as a
+// histogram per trap cause. Enabled with the `latency` feature.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::device::clint::{read_mtime, ticks_to_ns};
+use crate::hw::param::NHART;
+use crate::hw::riscv;
+use crate::sync::atomic::RelaxedCounter;
+
+/// Histogram buckets: <1, 1-2, 2-4, 4-8, 8-16, 16-32, 32-64 and 64+ us.
+pub const BUCKETS: usize = 8;
+// Cause codes 0-15, for exceptions and for interrupts.
+const CAUSES: usize = 16;
+
+/// Running max and average of the time one hart spends with interrupts
+/// disabled.
+pub struct InterruptLatencyTracker {
+    disabled_at: AtomicU64,
+    max: AtomicU64,   // Ticks.
+    total: AtomicU64, // Ticks.
+    count: AtomicU64,
+}
+
+impl InterruptLatencyTracker {
+    const fn new() -> Self {
+        InterruptLatencyTracker {
+            disabled_at: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// This hart's tracker.
+    pub fn this_hart() -> &'static Self {
+        &IRQ_OFF[riscv::read_tp() as usize]
+    }
+
+    /// Interrupts were just disabled.
+    pub fn disabled(&self) {
+        self.disabled_at.store(read_mtime(), Ordering::Relaxed);
+    }
+
+    /// Interrupts are about to be enabled again.
+    pub fn enabled(&self) {
+        let ticks = read_mtime() - self.disabled_at.load(Ordering::Relaxed);
+        self.max.fetch_max(ticks, Ordering::Relaxed);
+        // Only read back for reporting, nothing is ordered by these.
+        self.total.fetch_add_relaxed(ticks);
+        self.count.fetch_add_relaxed(1);
+    }
+
+    pub fn max_us(&self) -> u64 {
+        ticks_to_ns(self.max.load(Ordering::Relaxed)) / 1000
+    }
+
+    pub fn avg_us(&self) -> u64 {
+        let count = self.count.load(Ordering::Relaxed);
+        match count {
+            0 => 0,
+            _ => ticks_to_ns(self.total.load(Ordering::Relaxed) / count) / 1000,
+        }
+    }
+}
+
+static IRQ_OFF: [InterruptLatencyTracker; NHART] =
+    [const { InterruptLatencyTracker::new() }; NHART];
+
+// Exceptions first, then interrupts.
+static TRAP_HIST: [[AtomicU64; BUCKETS]; 2 * CAUSES] =
+    [const { [const { AtomicU64::new(0) }; BUCKETS] }; 2 * CAUSES];
+
+fn bucket(us: u64) -> usize {
+    match us {
+        0 => 0,
+        _ => core::cmp::min(us.ilog2() as usize + 1, BUCKETS - 1),
+    }
+}
+
+fn cause_index(cause: u64) -> usize {
+    let interrupt = (cause >> 63) as usize;
+    interrupt * CAUSES + (cause as usize & (CAUSES - 1))
+}
+
+/// Call at trap entry, pass the result to `trap_exit`.
+pub fn trap_enter() -> u64 {
+    read_mtime()
+}
+
+/// Count a trap of `cause` that came in at `entered` in the histogram.
+pub fn trap_exit(cause: u64, entered: u64) {
+    let us = ticks_to_ns(read_mtime() - entered) / 1000;
+    TRAP_HIST[cause_index(cause)][bucket(us)].fetch_add_relaxed(1);
+}
+
+/// Log the interrupts off times and every non empty trap histogram.
+pub fn dump() {
+    for (hart, tracker) in IRQ_OFF.iter().enumerate() {
+        log!(
+            Info,
+            "Hart {} interrupts off: max {}us, avg {}us",
+            hart,
+            tracker.max_us(),
+            tracker.avg_us()
+        );
+    }
+    for (idx, hist) in TRAP_HIST.iter().enumerate() {
+        let counts = hist.each_ref().map(|c| c.load(Ordering::Relaxed));
+        if counts.iter().any(|&c| c != 0) {
+            let kind = if idx >= CAUSES {
+                "interrupt"
+            } else {
+                "exception"
+            };
+            log!(
+                Info,
+                "Trap {} {} latency (<1, 1, 2, 4, 8, 16, 32, 64+ us): {:?}",
+                kind,
+                idx % CAUSES,
+                counts
+            );
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Kernel message buffer, what `dmesg` would read.
+// Every log! message is saved here as well as printed, so it isn't
+// lost once it scrolls off the UART. Unlike the console ring this only
+// holds log! output, and is meant to be read back at runtime.
+use core::fmt::{Error, Write};
+
+use crate::debug::console_ring::Console;
+use crate::hw::riscv::InterruptGuard;
+use crate::lock::mutex::Mutex;
+
+pub const SYSLOG_SIZE: usize = 65536;
+
+/// `syslog` command: copy out the most recent messages.
+pub const SYSLOG_READ: i32 = 2;
+/// `syslog` command: empty the buffer.
+pub const SYSLOG_CLEAR: i32 = 5;
+
+/// The last SYSLOG_SIZE bytes of log messages.
+pub struct Syslog {
+    buf: [u8; SYSLOG_SIZE],
+    head: usize, // Next byte to write.
+    len: usize,  // Valid bytes, up to SYSLOG_SIZE.
+}
+
+static SYSLOG: Mutex<Syslog> = Mutex::new(Syslog {
+    buf: [0; SYSLOG_SIZE],
+    head: 0,
+    len: 0,
+});
+
+impl Syslog {
+    fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.buf[self.head] = b;
+            self.head = (self.head + 1) % SYSLOG_SIZE;
+        }
+        self.len = core::cmp::min(self.len + bytes.len(), SYSLOG_SIZE);
+    }
+
+    /// Copy the last `out.len()` bytes, oldest first, into `out`.
+    /// Returns the number of bytes copied.
+    fn read(&self, out: &mut [u8]) -> usize {
+        let n = core::cmp::min(out.len(), self.len);
+        let start = (self.head + SYSLOG_SIZE - n) % SYSLOG_SIZE;
+        for (i, b) in out[..n].iter_mut().enumerate() {
+            *b = self.buf[(start + i) % SYSLOG_SIZE];
+        }
+        n
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+/// Writer for log!: sends each piece of the formatted message to the
+/// console and appends it to the kernel message buffer, so the message
+/// is only formatted once.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        Console.write_str(out)?;
+        // A trap that logs while we hold the buffer would spin forever.
+        let _guard = InterruptGuard::new();
+        SYSLOG.lock().push(out.as_bytes());
+        Ok(())
+    }
+}
+
+/// Kernel side of the syslog system call. SYSLOG_READ fills `buf` with
+/// the most recent messages and returns the number of bytes copied,
+/// SYSLOG_CLEAR empties the buffer and returns 0. Anything else is -1.
+pub fn syslog(cmd: i32, buf: &mut [u8]) -> i32 {
+    let _guard = InterruptGuard::new();
+    let mut log = SYSLOG.lock();
+    match cmd {
+        SYSLOG_READ => log.read(buf) as i32,
+        SYSLOG_CLEAR => {
+            log.clear();
+            0
+        }
+        _ => -1,
+    }
+}
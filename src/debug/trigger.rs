@@ -0,0 +1,72 @@
+//! Hardware breakpoints using the RISC-V debug trigger module.
+// Reference: RISC-V External Debug Support, chapter 5 (Trigger Module).
+// The trigger CSRs can only be accessed from machine mode, so these
+// must be called during boot or from the machine mode trap handler.
+use crate::hw::riscv::*;
+
+/// We expose at most this many triggers, indexed 0..MAX_TRIGGERS.
+pub const MAX_TRIGGERS: usize = 4;
+
+// tdata1 layout for an address/data match trigger (mcontrol, type 2).
+// action = 0 (raise a breakpoint exception), match = 0 (address equal).
+const MCONTROL_TYPE: usize = 2 << 60;
+const TDATA1_TYPE_MASK: usize = 0xF << 60; // 0: no trigger at this index.
+const MCONTROL_S: usize = 1 << 4; // Match in supervisor mode.
+const MCONTROL_U: usize = 1 << 3; // Match in user mode.
+const MCONTROL_EXECUTE: usize = 1 << 2;
+const MCONTROL_STORE: usize = 1 << 1;
+const MCONTROL_LOAD: usize = 1 << 0;
+
+/// Handle on the hart's debug triggers.
+pub struct Trigger;
+
+impl Trigger {
+    /// Number of triggers this hart supports, up to MAX_TRIGGERS.
+    /// Follows the debug spec's enumeration: tselect is WARL, so selecting
+    /// a trigger that doesn't exist reads back a different index, and an
+    /// index that does read back may still have no trigger, tdata1 type 0.
+    pub fn count() -> usize {
+        let mut count = 0;
+        while count < MAX_TRIGGERS {
+            write_tselect(count);
+            if read_tselect() != count || read_tdata1() & TDATA1_TYPE_MASK == 0 {
+                break;
+            }
+            count += 1;
+        }
+        count
+    }
+
+    /// Break on execution of the instruction at `addr` in supervisor or
+    /// user mode. Returns the index of the trigger used, or None if every
+    /// trigger is already in use or none can match on execution.
+    pub fn set_breakpoint(addr: usize) -> Option<usize> {
+        let in_use = MCONTROL_EXECUTE | MCONTROL_STORE | MCONTROL_LOAD;
+        let config = MCONTROL_TYPE | MCONTROL_S | MCONTROL_U | MCONTROL_EXECUTE;
+        for index in 0..Self::count() {
+            write_tselect(index);
+            if read_tdata1() & in_use == 0 {
+                // Disable the trigger while changing its address.
+                write_tdata1(0);
+                write_tdata2(addr);
+                write_tdata1(config);
+                // tdata1 is WARL, a trigger that can't do this keeps
+                // whatever it supports instead.
+                let check = TDATA1_TYPE_MASK | MCONTROL_EXECUTE;
+                if read_tdata1() & check == config & check {
+                    return Some(index);
+                }
+                write_tdata1(0);
+            }
+        }
+        None
+    }
+
+    /// Disable trigger `index`.
+    pub fn clear(index: usize) {
+        assert!(index < MAX_TRIGGERS, "No such trigger: {}", index);
+        write_tselect(index);
+        write_tdata1(0);
+        write_tdata2(0);
+    }
+}
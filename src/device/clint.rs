@@ -1,7 +1,43 @@
 //! Core local interruptor (timer interrupts).
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::boot::bootinfo;
+use crate::boot::fdt::Fdt;
 use crate::hw::param;
 use crate::hw::riscv;
 
+/// mtime ticks per second on the QEMU virt machine, used when the board
+/// doesn't tell us otherwise.
+pub const DEFAULT_CLINT_FREQ_HZ: u64 = 10_000_000;
+
+/// mtime ticks per second on this board.
+pub static CLINT_FREQ_HZ: AtomicU64 = AtomicU64::new(DEFAULT_CLINT_FREQ_HZ);
+
+/// Record the mtime frequency. None reads /cpus/timebase-frequency from
+/// this hart's boot device tree, keeping the default if it has none.
+/// Called from machine mode, after bootinfo::init.
+pub fn init(timebase_hz: Option<u64>) {
+    let hz = timebase_hz.or_else(|| {
+        let info = bootinfo::get(riscv::read_mhartid() as usize);
+        let fdt = unsafe { Fdt::from_ptr(info.dtb_ptr) }?;
+        fdt.timebase_frequency().map(u64::from)
+    });
+    if let Some(hz) = hz {
+        assert!(hz != 0, "mtime frequency can't be 0 Hz");
+        CLINT_FREQ_HZ.store(hz, Ordering::Relaxed);
+    }
+}
+
+pub fn freq_hz() -> u64 {
+    CLINT_FREQ_HZ.load(Ordering::Relaxed)
+}
+
+/// Convert mtime ticks to nanoseconds.
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    // Widen so large tick counts don't overflow.
+    (ticks as u128 * 1_000_000_000 / freq_hz() as u128) as u64
+}
+
 /// Get the current CLINT time.
 pub fn read_mtime() -> u64 {
     let base = param::CLINT_BASE as *mut u64;
@@ -28,3 +64,64 @@ pub fn set_mtimecmp(interval: u64) {
             .write_volatile(mtime + interval as usize);
     }
 }
+
+pub const CLOCK_REALTIME: u32 = 0;
+pub const CLOCK_MONOTONIC: u32 = 1;
+
+/// Fallback wall clock time at boot, 2023-01-01T00:00:00Z, in ns since
+/// the Unix epoch. There is no RTC driver to read the real one from.
+const FALLBACK_EPOCH_NS: u64 = 1_672_531_200 * 1_000_000_000;
+
+/// Wall clock time at mtime 0, in ns since the Unix epoch.
+pub static BOOT_EPOCH_NS: AtomicU64 = AtomicU64::new(FALLBACK_EPOCH_NS);
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    fn from_ns(ns: u64) -> Self {
+        Timespec {
+            tv_sec: (ns / 1_000_000_000) as i64,
+            tv_nsec: (ns % 1_000_000_000) as i64,
+        }
+    }
+}
+
+/// Current time of clock `clk_id`, or None for an unknown clock (EINVAL
+/// to a system call).
+pub fn clock_gettime(clk_id: u32) -> Option<Timespec> {
+    let now = ticks_to_ns(read_mtime());
+    match clk_id {
+        CLOCK_MONOTONIC => Some(Timespec::from_ns(now)),
+        CLOCK_REALTIME => Some(Timespec::from_ns(
+            BOOT_EPOCH_NS.load(Ordering::Relaxed) + now,
+        )),
+        _ => None,
+    }
+}
+
+/// Arm this hart's next timer interrupt, one second from now.
+pub fn schedule_next_tick() {
+    set_mtimecmp(freq_hz()); // May want to speed this up in the future.
+}
+
+/// Raise a machine software interrupt on `hart`.
+// msip regs are 4 bytes per hart at base + 0x0
+pub fn set_msip(hart: usize) {
+    let base = param::CLINT_BASE as *mut u32;
+    unsafe {
+        base.add(hart).write_volatile(1);
+    }
+}
+
+/// Clear the machine software interrupt on `hart`.
+pub fn clear_msip(hart: usize) {
+    let base = param::CLINT_BASE as *mut u32;
+    unsafe {
+        base.add(hart).write_volatile(0);
+    }
+}
@@ -11,14 +11,73 @@ use crate::lock::mutex::*;
 const IER: usize = 1; // Interrupt Enable Register
 const LCR: usize = 3; // Line Control Register (baud rate stuff)
 const FCR: usize = 2; // FIFO Control Register (see uart layout in reference)
-                      //const LSR: usize = 2; // Line Status Register (ready to rx, ready to tx signals)
+const LSR: usize = 5; // Line Status Register (ready to rx, ready to tx signals)
+const LSR_THRE: u8 = 1 << 5; // Transmit holding register empty.
+const LSR_DR: u8 = 1 << 0; // Data ready.
 
 pub static WRITER: Mutex<Uart> = Uart::new();
 
+/// Bare minimum 16550A setup so we can print before anything else
+/// (traps, vm, allocators) is initialized. Only raw MMIO writes, no
+/// locks and no allocation. `Uart::init` still does the full setup.
+pub fn uart_early_init() {
+    let ptr = UART_BASE as *mut u8;
+    unsafe {
+        ptr.add(IER).write_volatile(0x0);
+        // Set DLAB to write the divisor latch, 38.4k baud.
+        ptr.add(LCR).write_volatile(1 << 7);
+        ptr.add(0).write_volatile(0x03);
+        ptr.add(1).write_volatile(0x00);
+        // Clear DLAB, 8 bit words, no parity.
+        ptr.add(LCR).write_volatile(3);
+    }
+}
+
+/// Busy wait print for early boot, see `uart_early_init`.
+pub fn uart_early_print(s: &str) {
+    for c in s.bytes() {
+        uart_early_putc(c);
+    }
+}
+
+/// Busy wait until the transmitter is free, then send c. Takes no locks,
+/// so it is also safe from trap handlers.
+pub fn uart_early_putc(c: u8) {
+    let ptr = UART_BASE as *mut u8;
+    unsafe {
+        while ptr.add(LSR).read_volatile() & LSR_THRE == 0 {
+            core::hint::spin_loop();
+        }
+        ptr.add(0).write_volatile(c);
+    }
+}
+
+/// Busy wait for a received byte, see `uart_early_putc`.
+pub fn uart_early_getc() -> u8 {
+    let ptr = UART_BASE as *mut u8;
+    unsafe {
+        while ptr.add(LSR).read_volatile() & LSR_DR == 0 {
+            core::hint::spin_loop();
+        }
+        ptr.add(0).read_volatile()
+    }
+}
+
 pub struct Uart {
     base_address: usize,
 }
 
+/// Writer straight to the UART with `uart_early_putc`, taking no locks.
+/// For the panic path, where any lock may be held by the panicking hart.
+pub struct EarlyUart;
+
+impl Write for EarlyUart {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        uart_early_print(out);
+        Ok(())
+    }
+}
+
 impl Write for Uart {
     fn write_str(&mut self, out: &str) -> Result<(), Error> {
         for c in out.bytes() {
@@ -72,7 +131,7 @@ impl Uart {
     pub fn get(&mut self) -> Option<u8> {
         let ptr = self.base_address as *mut u8;
         unsafe {
-            if ptr.add(5).read_volatile() & 1 == 0 {
+            if ptr.add(LSR).read_volatile() & 1 == 0 {
                 // The DR bit is 0, meaning no data
                 None
             } else {
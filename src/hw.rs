@@ -1,5 +1,10 @@
 //! Target-hardware parameters and utilities.
 pub mod param;
+// The counter CSRs only exist on the target.
+#[cfg(not(test))]
+pub mod perf;
+pub mod pmp;
+pub mod qemu_test;
 pub mod riscv;
 
 use crate::device::clint;
@@ -23,8 +28,8 @@ pub struct Hart {
 /// We write the machine mode trap vector register (mtvec) with the address
 /// of our `src/asm` trap handler function.
 pub fn timerinit() {
-    let interval = 10_000_000; // May want to speed this up in the future.
-    clint::set_mtimecmp(interval);
+    clint::init(None);
+    clint::schedule_next_tick();
 
     // Set the machine trap vector to hold fn ptr to timervec.
     let timervec_fn = trap::__mtrapvec;
@@ -35,7 +40,7 @@ pub fn timerinit() {
     mstatus |= MSTATUS_MIE;
     write_mstatus(mstatus);
 
-    // Enable machine-mode timer interrupts.
-    let mie = read_mie() | MIE_MTIE;
+    // Enable machine-mode timer and software (IPI) interrupts.
+    let mie = read_mie() | MIE_MTIE | MIE_MSIE;
     write_mie(mie);
 }
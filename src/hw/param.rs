@@ -47,6 +47,9 @@ extern "C" {
     static mut _intstacks_end: usize;
 }
 
+/// QEMU test (exit) device base address.
+pub const VIRT_TEST_BASE: usize = 0x100000;
+
 /// CLINT base address.
 pub const CLINT_BASE: usize = 0x2000000;
 
@@ -84,6 +87,13 @@ pub fn stacks_end() -> *mut usize {
     unsafe { addr_of_mut!(_stacks_end) }
 }
 
+/// Is sp inside the kernel's per hart stacks or trap stacks? A machine
+/// mode trap can land while the supervisor handler runs on its trap stack.
+pub fn is_valid_kstack(sp: usize) -> bool {
+    (stacks_start().addr() <= sp && sp < stacks_end().addr())
+        || (intstacks_start().addr() <= sp && sp < intstacks_end().addr())
+}
+
 pub fn intstacks_start() -> *mut usize {
     unsafe { addr_of_mut!(_intstacks_start) }
 }
@@ -96,10 +106,24 @@ pub fn dram_end() -> *mut usize {
     unsafe { addr_of_mut!(_memory_end) }
 }
 
-pub static PAGE_SIZE: usize = 4096;
+pub const PAGE_SIZE: usize = 4096;
+pub const PAGE_SHIFT: usize = 12;
+pub const PAGE_MASK: usize = PAGE_SIZE - 1;
+
+/// Round `addr` down to the start of its page.
+pub const fn page_align_down(addr: usize) -> usize {
+    addr & !PAGE_MASK
+}
+
+/// Round `addr` up to a page boundary.
+pub const fn page_align_up(addr: usize) -> usize {
+    page_align_down(addr + PAGE_MASK)
+}
 
 // Run parameters
 pub const NHART: usize = 2;
+pub const MAX_PROCS: usize = 64; // Must be a multiple of 64.
+pub const STACK_SIZE: usize = 0x3000; // Per hart kernel stack + guard page, as in entry.s.
 
 // Unnecessary.
 pub static BANNER: &str = r#"
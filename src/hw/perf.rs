@@ -0,0 +1,47 @@
+//! Hardware performance counters.
+// The unprivileged cycle, instret and time CSRs. Supervisor mode can
+// only read them because _start sets mcounteren.
+use core::arch::asm;
+
+/// Handle on the hart's performance counters.
+pub struct Perf;
+
+impl Perf {
+    /// Cycles executed by this hart.
+    pub fn read_cycle() -> u64 {
+        let count: u64;
+        unsafe {
+            asm!("csrr {}, cycle", out(reg) count);
+        }
+        count
+    }
+
+    /// Instructions retired by this hart.
+    pub fn read_instret() -> u64 {
+        let count: u64;
+        unsafe {
+            asm!("csrr {}, instret", out(reg) count);
+        }
+        count
+    }
+
+    /// Wall clock ticks, at the CLINT frequency.
+    pub fn read_time() -> u64 {
+        let count: u64;
+        unsafe {
+            asm!("csrr {}, time", out(reg) count);
+        }
+        count
+    }
+
+    /// High half of the cycle counter. Only exists on RV32, where the
+    /// counters are read in two halves.
+    #[cfg(target_arch = "riscv32")]
+    pub fn rdcycleh() -> u32 {
+        let count: u32;
+        unsafe {
+            asm!("csrr {}, cycleh", out(reg) count);
+        }
+        count
+    }
+}
@@ -0,0 +1,84 @@
+//! Physical memory protection.
+// Reference: RISC-V Privileged ISA, section 3.7. On RV64 the config
+// bytes of entries 0-7 all live in pmpcfg0, one byte per entry.
+//
+// PMP can't tell supervisor from user mode, both are checked against
+// the same entries. So this only fences both off from physical memory
+// outside DRAM and MMIO, keeping user code out of kernel memory is still
+// the page tables' job. Must be called from machine mode.
+use core::arch::asm;
+use core::ops::BitOr;
+
+use super::param::DRAM_BASE;
+use super::riscv::{read_pmpcfg0, write_pmpaddr0, write_pmpcfg0};
+
+/// Number of entries we manage, all configured through pmpcfg0.
+pub const PMP_ENTRIES: usize = 8;
+
+/// One entry's config byte.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct PmpCfg(u8);
+
+impl PmpCfg {
+    pub const R: PmpCfg = PmpCfg(1 << 0);
+    pub const W: PmpCfg = PmpCfg(1 << 1);
+    pub const X: PmpCfg = PmpCfg(1 << 2);
+    /// Disabled, matches nothing.
+    pub const OFF: PmpCfg = PmpCfg(0);
+    /// Top of range: matches [previous entry's addr, this entry's addr).
+    pub const TOR: PmpCfg = PmpCfg(1 << 3);
+    /// Naturally aligned power of two region.
+    pub const NAPOT: PmpCfg = PmpCfg(3 << 3);
+    /// Lock the entry and enforce it on machine mode too.
+    pub const L: PmpCfg = PmpCfg(1 << 7);
+}
+
+impl BitOr for PmpCfg {
+    type Output = PmpCfg;
+
+    fn bitor(self, rhs: PmpCfg) -> PmpCfg {
+        PmpCfg(self.0 | rhs.0)
+    }
+}
+
+macro_rules! write_pmpaddr {
+    ($csr:literal, $addr:expr) => {
+        unsafe {
+            asm!(concat!("csrw ", $csr, ", {}"), in(reg) $addr);
+        }
+    };
+}
+
+/// Program entry `idx` with byte address `addr` and config `cfg`.
+/// pmpaddr holds bits 55:2 of the address.
+pub fn pmp_set_entry(idx: usize, addr: usize, cfg: PmpCfg) {
+    let pmpaddr = addr >> 2;
+    match idx {
+        0 => write_pmpaddr0(pmpaddr as u64),
+        1 => write_pmpaddr!("pmpaddr1", pmpaddr),
+        2 => write_pmpaddr!("pmpaddr2", pmpaddr),
+        3 => write_pmpaddr!("pmpaddr3", pmpaddr),
+        4 => write_pmpaddr!("pmpaddr4", pmpaddr),
+        5 => write_pmpaddr!("pmpaddr5", pmpaddr),
+        6 => write_pmpaddr!("pmpaddr6", pmpaddr),
+        7 => write_pmpaddr!("pmpaddr7", pmpaddr),
+        _ => panic!("No such PMP entry: {}", idx),
+    }
+    let shift = idx * 8;
+    let cfgs = (read_pmpcfg0() & !(0xff << shift)) | ((cfg.0 as usize) << shift);
+    write_pmpcfg0(cfgs as u64);
+}
+
+/// Give supervisor and user mode RWX access to DRAM in [start, end) and
+/// RW access to the MMIO devices below DRAM_BASE. Any other physical
+/// address matches no entry, and so faults.
+pub fn pmp_allow_user_dram(start: usize, end: usize) {
+    assert!(DRAM_BASE.addr() <= start && start < end);
+    pmp_set_entry(0, DRAM_BASE.addr(), PmpCfg::TOR | PmpCfg::R | PmpCfg::W);
+    // Lower bound of the DRAM range for entry 2, matches nothing itself.
+    pmp_set_entry(1, start, PmpCfg::OFF);
+    pmp_set_entry(2, end, PmpCfg::TOR | PmpCfg::R | PmpCfg::W | PmpCfg::X);
+    for idx in 3..PMP_ENTRIES {
+        pmp_set_entry(idx, 0, PmpCfg::OFF);
+    }
+}
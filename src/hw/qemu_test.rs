@@ -0,0 +1,30 @@
+//! QEMU virt test device, used to end a test run.
+// Writing to the device register makes QEMU exit; 0x5555 means pass,
+// and 0x3333 with an exit code in the upper 16 bits means fail.
+// See qemu/hw/misc/sifive_test.c.
+use crate::hw::param::VIRT_TEST_BASE;
+
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_FAIL: u32 = 0x3333;
+
+fn finish(val: u32) -> ! {
+    let ptr = VIRT_TEST_BASE as *mut u32;
+    unsafe {
+        ptr.write_volatile(val);
+    }
+    // Not running under QEMU, nothing to exit to.
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Exit QEMU with status 0.
+pub fn qemu_exit_success() -> ! {
+    finish(FINISHER_PASS)
+}
+
+/// Exit QEMU with a non zero status `code`. The device only has 16 bits
+/// for it.
+pub fn qemu_exit_failure(code: u16) -> ! {
+    finish(((code as u32) << 16) | FINISHER_FAIL)
+}
@@ -1,6 +1,9 @@
 //! Rust wrappers around RISC-V routines
 use core::arch::asm;
 
+use crate::hw::param::NHART;
+use crate::ipc::ipi_ring::{self, IpiMsg};
+
 /// Machine previous protection mode.
 pub const MSTATUS_MPP_MASK: u64 = 3 << 11; // Mask for bit tricks
 pub const MSTATUS_MPP_M: u64 = 3 << 11; // Machine
@@ -15,6 +18,19 @@ pub const SSTATUS_UPIE: u64 = 1 << 4; // User Previous Interrupt Enable
 pub const SSTATUS_SIE: u64 = 1 << 1; // Supervisor Interrupt Enable
 pub const SSTATUS_UIE: u64 = 1 << 0; // User Interrupt Enable
 
+/// Machine software interrupt (mcause), raised through CLINT msip.
+pub const MCAUSE_SOFT: u64 = (1 << 63) | 3;
+
+/// Supervisor trap causes (scause with the interrupt bit clear).
+pub const SCAUSE_STORE_PAGE_FAULT: u64 = 15;
+/// Breakpoint exception (scause), from ebreak.
+pub const SCAUSE_BREAKPOINT: u64 = 3;
+/// Supervisor software interrupt (scause).
+pub const SCAUSE_SOFT: u64 = (1 << 63) | 1;
+
+/// Supervisor software interrupt pending, in mip and sip.
+pub const MIP_SSIP: u64 = 1 << 1;
+
 /// Machine-mode Interrupt Enable
 pub const MIE_MEIE: u64 = 1 << 11; // external
 pub const MIE_MTIE: u64 = 1 << 7; // timer
@@ -70,6 +86,15 @@ pub fn read_scause() -> u64 {
     cause
 }
 
+/// Read stval := faulting address (or instruction) of the last supervisor trap.
+pub fn read_stval() -> usize {
+    let val: usize;
+    unsafe {
+        asm!("csrr {}, stval", out(reg) val);
+    }
+    val
+}
+
 /// Set mepc := machine exception program counter.
 /// (what instr (address) to go to from exception.)
 pub fn write_mepc(addr: *const ()) {
@@ -86,6 +111,36 @@ pub fn read_mepc() -> usize {
     addr
 }
 
+/// sepc := supervisor exception program counter.
+pub fn read_sepc() -> usize {
+    let addr: usize;
+    unsafe {
+        asm!("csrr {}, sepc", out(reg) addr);
+    }
+    addr
+}
+
+pub fn write_sepc(addr: usize) {
+    unsafe {
+        asm!("csrw sepc, {}", in(reg) addr);
+    }
+}
+
+/// sscratch holds the interrupted sp while in the supervisor trap handler.
+pub fn read_sscratch() -> usize {
+    let val: usize;
+    unsafe {
+        asm!("csrr {}, sscratch", out(reg) val);
+    }
+    val
+}
+
+pub fn write_sscratch(val: usize) {
+    unsafe {
+        asm!("csrw sscratch, {}", in(reg) val);
+    }
+}
+
 pub fn read_sstatus() -> u64 {
     let status: u64;
     unsafe {
@@ -100,6 +155,20 @@ pub fn write_status(status: u64) {
     }
 }
 
+pub fn read_mip() -> u64 {
+    let x: u64;
+    unsafe {
+        asm!("csrr {}, mip", out(reg) x);
+    }
+    x
+}
+
+pub fn write_mip(ip: u64) {
+    unsafe {
+        asm!("csrw mip, {}", in(reg) ip);
+    }
+}
+
 // Enable sup mode interrupt and exception.
 pub fn read_sip() -> u64 {
     let x: u64;
@@ -159,6 +228,60 @@ pub fn write_satp(pt: usize) {
     }
 }
 
+/// Address translation modes, satp.MODE.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SatpMode {
+    Bare,
+    Sv32,
+    Sv39,
+    Sv48,
+}
+
+impl SatpMode {
+    /// The MODE field value, already shifted into place (RV64 encoding,
+    /// except Sv32 which only exists on RV32).
+    pub const fn bits(self) -> usize {
+        match self {
+            SatpMode::Bare => 0,
+            SatpMode::Sv32 => 1 << 31,
+            SatpMode::Sv39 => 8 << 60,
+            SatpMode::Sv48 => 9 << 60,
+        }
+    }
+}
+
+// What detect_satp_mode found, as a SatpMode discriminant.
+static SATP_MODE: core::sync::atomic::AtomicU8 = core::sync::atomic::AtomicU8::new(0);
+
+/// Find the most capable translation mode this hart supports. satp is
+/// WARL and ignores writes of an unsupported MODE, so try each mode and
+/// read it back, leaving satp 0 (Bare). Must run in machine mode, a
+/// mode that sticks turns on translation for supervisor mode at once.
+pub fn detect_satp_mode() -> SatpMode {
+    let mut found = SatpMode::Bare;
+    for mode in [SatpMode::Sv48, SatpMode::Sv39] {
+        write_satp(mode.bits());
+        let stuck = read_satp() == mode.bits();
+        write_satp(0);
+        if stuck {
+            found = mode;
+            break;
+        }
+    }
+    SATP_MODE.store(found as u8, core::sync::atomic::Ordering::Relaxed);
+    found
+}
+
+/// The mode detect_satp_mode found at boot.
+pub fn satp_mode() -> SatpMode {
+    match SATP_MODE.load(core::sync::atomic::Ordering::Relaxed) {
+        1 => SatpMode::Sv32,
+        2 => SatpMode::Sv39,
+        3 => SatpMode::Sv48,
+        _ => SatpMode::Bare,
+    }
+}
+
 /// medeleg := machine exception delegation (to supervisor mode)
 pub fn read_medeleg() -> u64 {
     let med: u64;
@@ -174,6 +297,17 @@ pub fn write_medeleg(med: u64) {
     }
 }
 
+/// mcounteren := which counters lower privilege modes may read.
+pub const MCOUNTEREN_CY: u64 = 1 << 0;
+pub const MCOUNTEREN_TM: u64 = 1 << 1;
+pub const MCOUNTEREN_IR: u64 = 1 << 2;
+
+pub fn write_mcounteren(mce: u64) {
+    unsafe {
+        asm!("csrw mcounteren, {}", in(reg) mce);
+    }
+}
+
 /// mideleg := machine interrupt delegation (to supervisor mode)
 pub fn read_mideleg() -> u64 {
     let mid: u64;
@@ -288,7 +422,191 @@ pub fn read_stvec() -> usize {
 /// The `zero, zero` arguments to `sfence.vma` insn mean
 /// we completely flush every TLB entry for all ASIDs.
 pub fn flush_tlb() {
+    unsafe { sfence_vma() }
+}
+
+/// Flush this hart's TLB entries for the page at `vaddr` in address
+/// space `asid`. Global mappings are left alone.
+pub fn tlb_flush_page(vaddr: usize, asid: u16) {
+    unsafe {
+        asm!("sfence.vma {}, {}", in(reg) vaddr, in(reg) asid as usize);
+    }
+}
+
+/// Flush this hart's TLB entries for every non-global address in
+/// address space `asid`.
+pub fn tlb_flush_asid(asid: u16) {
+    unsafe {
+        asm!("sfence.vma zero, {}", in(reg) asid as usize);
+    }
+}
+
+/// The ASID field of satp, the current address space.
+pub fn current_asid() -> u16 {
+    ((read_satp() >> 44) & 0xffff) as u16
+}
+
+// Which fence goes where:
+// - fence rw, rw: order this hart's memory accesses, e.g. before another
+//   hart (or a DMA device) reads data we wrote, and before we read data
+//   it wrote. Use before starting and after completing a DMA transfer.
+// - fence.i: after writing instructions (loading an ELF, setting a
+//   breakpoint) and before executing them, on this hart.
+// - sfence.vma: after changing a page table in use, before relying on
+//   the new mapping. Only affects this hart's TLB.
+
+/// Full data memory fence, `fence rw, rw`.
+/// # Safety
+/// Always safe to execute, unsafe only to mark it as an ordering primitive.
+pub unsafe fn fence_rw() {
+    asm!("fence rw, rw");
+}
+
+/// Synchronize this hart's instruction fetches with its earlier stores.
+/// # Safety
+/// See `fence_rw`.
+pub unsafe fn fence_i() {
+    asm!("fence.i");
+}
+
+// How many times to retry sending an IPI to a hart whose ring is full.
+const IPI_SEND_RETRIES: usize = 1000;
+
+/// Make code just written to [vaddr, vaddr + len) visible to instruction
+/// fetch on every hart. fence.i has no range form, so this synchronizes
+/// the whole instruction cache. The other harts flush when they take the
+/// IPI, this doesn't wait for them. If a hart's ring stays full (it
+/// isn't taking interrupts), fails with that hart's id; this hart and
+/// the harts before it are already flushed or asked to.
+pub fn flush_icache_range(_vaddr: usize, _len: usize) -> Result<(), usize> {
+    let me = read_tp() as usize;
+    unsafe {
+        // The new code must be visible to the other harts' fetches too.
+        fence_rw();
+        fence_i();
+    }
+    for hart in (0..NHART).filter(|&hart| hart != me) {
+        // Ring full, give the hart a while to drain it.
+        let sent = (0..IPI_SEND_RETRIES).any(|_| {
+            let sent = ipi_ring::send(hart, IpiMsg::IcacheFlush);
+            if !sent {
+                core::hint::spin_loop();
+            }
+            sent
+        });
+        if !sent {
+            return Err(hart);
+        }
+    }
+    Ok(())
+}
+
+/// Flush this hart's TLB for every address and ASID.
+/// # Safety
+/// See `fence_rw`.
+pub unsafe fn sfence_vma() {
+    asm!("sfence.vma zero, zero");
+}
+
+/// tselect := debug trigger select.
+/// The trigger CSRs are only accessible from machine mode.
+pub fn read_tselect() -> usize {
+    let idx: usize;
+    unsafe {
+        asm!("csrr {}, tselect", out(reg) idx);
+    }
+    idx
+}
+
+pub fn write_tselect(idx: usize) {
+    unsafe {
+        asm!("csrw tselect, {}", in(reg) idx);
+    }
+}
+
+/// tdata1 := config of the selected trigger (type, match mode, action).
+pub fn read_tdata1() -> usize {
+    let data: usize;
+    unsafe {
+        asm!("csrr {}, tdata1", out(reg) data);
+    }
+    data
+}
+
+pub fn write_tdata1(data: usize) {
     unsafe {
-        asm!("sfence.vma zero, zero");
+        asm!("csrw tdata1, {}", in(reg) data);
+    }
+}
+
+/// tdata2 := match value (address) of the selected trigger.
+pub fn read_tdata2() -> usize {
+    let data: usize;
+    unsafe {
+        asm!("csrr {}, tdata2", out(reg) data);
+    }
+    data
+}
+
+pub fn write_tdata2(data: usize) {
+    unsafe {
+        asm!("csrw tdata2, {}", in(reg) data);
+    }
+}
+
+/// Turn off supervisor interrupts on this hart.
+/// Returns whether they were on before.
+pub fn interrupt_disable() -> bool {
+    let old: u64;
+    unsafe {
+        asm!("csrrci {}, sstatus, 2", out(reg) old);
+    }
+    old & SSTATUS_SIE != 0
+}
+
+/// Turn on supervisor interrupts on this hart.
+pub fn interrupt_enable() {
+    unsafe {
+        asm!("csrsi sstatus, 2");
+    }
+}
+
+/// Put supervisor interrupts back the way `interrupt_disable` found them.
+pub fn interrupt_restore(was_enabled: bool) {
+    if was_enabled {
+        interrupt_enable();
+    }
+}
+
+/// Interrupts are off for as long as this guard lives, and restored to
+/// their previous state when it drops.
+pub struct InterruptGuard {
+    was_enabled: bool,
+}
+
+impl InterruptGuard {
+    pub fn new() -> Self {
+        let was_enabled = interrupt_disable();
+        #[cfg(feature = "latency")]
+        if was_enabled {
+            crate::debug::latency::InterruptLatencyTracker::this_hart().disabled();
+        }
+        InterruptGuard { was_enabled }
+    }
+}
+
+impl Default for InterruptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "latency")]
+        if self.was_enabled {
+            crate::debug::latency::InterruptLatencyTracker::this_hart().enabled();
+        }
+        interrupt_restore(self.was_enabled);
     }
 }
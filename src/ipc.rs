@@ -0,0 +1,2 @@
+//! Inter hart communication.
+pub mod ipi_ring;
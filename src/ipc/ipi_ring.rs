@@ -0,0 +1,157 @@
+//! Lock free per hart message rings, delivered by software interrupt.
+// Sending a message pushes onto the target hart's ring and raises a
+// machine software interrupt there through the CLINT. The machine mode
+// handler forwards it as a supervisor software interrupt, which drains
+// the ring.
+//
+// Each slot carries a sequence number so a producer and a consumer can
+// never touch the same slot at once: for lap L around the ring a slot
+// is empty when seq == 2L and full when seq == 2L + 1. This lets every
+// slot start out zeroed.
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::device::clint;
+use crate::hw::param::NHART;
+use crate::hw::riscv;
+
+/// Messages one hart can send another.
+#[derive(Copy, Clone, Debug)]
+pub enum IpiMsg {
+    /// Flush this hart's TLB.
+    TlbShootdown,
+    /// Flush one page, at this virtual address in this ASID.
+    TlbFlushPage(usize, u16),
+    /// Synchronize this hart's instruction cache with new code.
+    IcacheFlush,
+    /// Hint that the process with this pid could be run here.
+    Migrate(usize),
+}
+
+struct Slot {
+    seq: AtomicUsize,
+    msg: UnsafeCell<MaybeUninit<IpiMsg>>,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Slot {
+            seq: AtomicUsize::new(0),
+            msg: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+}
+
+/// Bounded multi producer, multi consumer ring of N messages.
+pub struct IpiRing<const N: usize> {
+    head: AtomicUsize, // Next position to pop.
+    tail: AtomicUsize, // Next position to push.
+    slots: [Slot; N],
+}
+
+unsafe impl<const N: usize> Sync for IpiRing<N> {}
+
+impl<const N: usize> Default for IpiRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> IpiRing<N> {
+    pub const fn new() -> Self {
+        IpiRing {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            slots: [const { Slot::new() }; N],
+        }
+    }
+
+    /// Add msg to the ring. Returns false if the ring is full.
+    pub fn push(&self, msg: IpiMsg) -> bool {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % N];
+            let empty = 2 * (pos / N);
+            let seq = slot.seq.load(Ordering::Acquire);
+            if seq == empty {
+                match self.tail.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.msg.get()).write(msg) };
+                        slot.seq.store(empty + 1, Ordering::Release);
+                        return true;
+                    }
+                    Err(curr) => pos = curr,
+                }
+            } else if seq < empty {
+                // Still holding last lap's message.
+                return false;
+            } else {
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Take the oldest message off the ring, if there is one.
+    pub fn pop(&self) -> Option<IpiMsg> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % N];
+            let full = 2 * (pos / N) + 1;
+            let seq = slot.seq.load(Ordering::Acquire);
+            if seq == full {
+                match self.head.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let msg = unsafe { (*slot.msg.get()).assume_init_read() };
+                        slot.seq.store(full + 1, Ordering::Release);
+                        return Some(msg);
+                    }
+                    Err(curr) => pos = curr,
+                }
+            } else if seq < full {
+                // Nothing pushed here yet.
+                return None;
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// One ring per hart, indexed by hartid.
+pub static IPI_RINGS: [IpiRing<16>; NHART] = [const { IpiRing::new() }; NHART];
+
+/// Send msg to `hart` and interrupt it. Returns false if its ring is full.
+pub fn send(hart: usize, msg: IpiMsg) -> bool {
+    if !IPI_RINGS[hart].push(msg) {
+        return false;
+    }
+    clint::set_msip(hart);
+    true
+}
+
+/// Supervisor software interrupt: handle everything queued for this hart.
+pub fn handle_ipi() {
+    riscv::write_sip(riscv::read_sip() & !riscv::MIP_SSIP);
+    let hart = riscv::read_tp() as usize;
+    while let Some(msg) = IPI_RINGS[hart].pop() {
+        match msg {
+            IpiMsg::TlbShootdown => riscv::flush_tlb(),
+            IpiMsg::TlbFlushPage(vaddr, asid) => riscv::tlb_flush_page(vaddr, asid),
+            IpiMsg::IcacheFlush => unsafe { riscv::fence_i() },
+            IpiMsg::Migrate(pid) => {
+                log!(Debug, "Hart {} got migrate hint for pid {}", hart, pid);
+            }
+        }
+    }
+}
@@ -1,2 +1,4 @@
 //! Kernel locks.
 pub mod mutex;
+pub mod rwlock;
+pub mod ticketlock;
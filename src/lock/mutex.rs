@@ -54,6 +54,12 @@ impl<T> Mutex<T> {
         }
     }
 
+    /// Raw pointer to the data, ignoring the lock. For post-mortem
+    /// access when the holder may never release it.
+    pub fn as_ptr(&self) -> *mut T {
+        self.inner.get()
+    }
+
     /// Needs to satisfy an atomic swap (acquire)
     /// then a fence so loads and stores aren't reordered until
     /// after lock is acquired.
@@ -0,0 +1,98 @@
+//! Spinning reader-writer lock.
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::*;
+
+/// Returned from read locking a RwLock, derefs to the shared data.
+pub struct RwReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+/// Returned from write locking a RwLock, derefs mutably.
+pub struct RwWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> core::ops::Deref for RwReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<T> core::ops::Drop for RwReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<T> core::ops::Deref for RwWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<T> core::ops::DerefMut for RwWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+impl<T> core::ops::Drop for RwWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
+
+/// Many readers or one writer at a time. Readers spin while a writer
+/// holds the lock and writers spin until there are no readers, so a
+/// steady stream of readers can starve a writer.
+pub struct RwLock<T> {
+    state: AtomicI32, // Number of readers, or -1 when write locked.
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: AtomicI32::new(0),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    /// Shared access, spins while a writer holds the lock.
+    pub fn read(&self) -> RwReadGuard<'_, T> {
+        let mut curr = self.state.load(Ordering::Relaxed);
+        loop {
+            if curr < 0 {
+                spin_loop();
+                curr = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.state.compare_exchange_weak(
+                curr,
+                curr + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return RwReadGuard { lock: self },
+                Err(actual) => curr = actual,
+            }
+        }
+    }
+
+    /// Exclusive access, spins until there are no readers or writer.
+    pub fn write(&self) -> RwWriteGuard<'_, T> {
+        while self
+            .state
+            .compare_exchange_weak(0, -1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        RwWriteGuard { lock: self }
+    }
+}
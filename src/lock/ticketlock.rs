@@ -0,0 +1,60 @@
+//! Fair spinlock: harts get the lock in the order they asked for it.
+// The plain Mutex lets whichever hart's swap lands first through, so a
+// hart can lose the race indefinitely. Here each locker takes a ticket
+// and waits for its number to come up.
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Returned from successfully locking a ticket lock.
+pub struct TicketLockGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T> core::ops::Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<T> core::ops::DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+impl<T> core::ops::Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Only the holder writes now_serving, so a plain add is enough.
+        let serving = self.lock.now_serving.load(Ordering::Relaxed);
+        self.lock.now_serving.store(serving + 1, Ordering::Release);
+    }
+}
+
+pub struct TicketLock<T> {
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    pub const fn new(value: T) -> Self {
+        TicketLock {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        // Acquire pairs with the previous holder's Release in drop.
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop();
+        }
+        TicketLockGuard { lock: self }
+    }
+}
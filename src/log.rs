@@ -4,9 +4,8 @@ macro_rules! print
 {
     ($($args:tt)+) => ({
         use core::fmt::Write;
-        use crate::uart;
         // let _ = write!(uart::WRITER.lock(), $($args)+);
-        let _ = write!(uart::Uart::new().lock(), $($args)+);
+        let _ = write!(crate::debug::console_ring::Console, $($args)+);
     });
 }
 
@@ -23,6 +22,15 @@ macro_rules! println
     });
 }
 
+// Print and also save into the kernel message buffer.
+macro_rules! log_print
+{
+    ($($args:tt)+) => ({
+        use core::fmt::Write;
+        let _ = write!(crate::debug::syslog::LogWriter, $($args)+);
+    });
+}
+
 pub enum LogSeverity {
     Debug,
     Info,
@@ -41,29 +49,29 @@ pub enum LogSeverity {
 macro_rules! log
 {
     (Debug, $fmt:expr) => ({
-        print!(concat!("[DEBUG] ", $fmt, "\r\n"))
+        log_print!(concat!("[DEBUG] ", $fmt, "\r\n"))
     });
     (Info, $fmt:expr) => ({
-        print!(concat!("[INFO] ", $fmt, "\r\n"))
+        log_print!(concat!("[INFO] ", $fmt, "\r\n"))
     });
     (Warning, $fmt:expr) => ({
-        print!(concat!("[WARN] ", $fmt, "\r\n"))
+        log_print!(concat!("[WARN] ", $fmt, "\r\n"))
     });
     (Error, $fmt:expr) => ({
-        print!(concat!("[ERROR] ", $fmt, "\r\n"))
+        log_print!(concat!("[ERROR] ", $fmt, "\r\n"))
     });
 
     (Debug, $fmt:expr, $($args:tt)+) => ({
-        print!(concat!("[DEBUG] ", $fmt, "\r\n"), $($args)+)
+        log_print!(concat!("[DEBUG] ", $fmt, "\r\n"), $($args)+)
     });
     (Info, $fmt:expr, $($args:tt)+) => ({
-        print!(concat!("[INFO] ", $fmt, "\r\n"), $($args)+)
+        log_print!(concat!("[INFO] ", $fmt, "\r\n"), $($args)+)
     });
     (Warning, $fmt:expr, $($args:tt)+) => ({
-        print!(concat!("[WARN] ", $fmt, "\r\n"), $($args)+)
+        log_print!(concat!("[WARN] ", $fmt, "\r\n"), $($args)+)
     });
     (Error, $fmt:expr, $($args:tt)+) => ({
-        print!(concat!("[ERROR] ", $fmt, "\r\n"), $($args)+)
+        log_print!(concat!("[ERROR] ", $fmt, "\r\n"), $($args)+)
     });
 }
 
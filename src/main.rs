@@ -9,18 +9,25 @@
 #![feature(once_cell)]
 #![feature(unsized_fn_params)]
 #![allow(dead_code)]
+use core::fmt::Write;
 use core::panic::PanicInfo;
 extern crate alloc;
 
 #[macro_use]
 pub mod log;
 pub mod asm;
+pub mod boot;
+pub mod debug;
 pub mod device;
 pub mod hw;
+pub mod ipc;
 pub mod lock;
+pub mod proc;
+pub mod sync;
 pub mod trap;
 pub mod vm;
 
+use crate::boot::bootinfo::{self, BootInfo};
 use crate::device::uart;
 use crate::hw::param;
 use crate::hw::riscv::*;
@@ -33,14 +40,20 @@ fn panic(info: &PanicInfo) -> ! {
         Some(msg) => msg,
         None => &default,
     };
-    match info.location() {
-        None => {
-            println!("PANIC! {} at {}", msg, "No location provided");
-        }
-        Some(loc) => {
-            println!("PANIC! {} at {}:{}", msg, loc.file(), loc.line());
-        }
-    }
+    // Not println!, the console and its ring are locked and this hart may
+    // have panicked holding them, e.g. from a machine mode trap.
+    let mut out = uart::EarlyUart;
+    let _ = match info.location() {
+        None => write!(out, "PANIC! {} at No location provided\r\n", msg),
+        Some(loc) => write!(out, "PANIC! {} at {}:{}\r\n", msg, loc.file(), loc.line()),
+    };
+    uart::uart_early_print("---- last console output ----\r\n");
+    debug::console_ring::dump_console_ring();
+    #[cfg(feature = "alloc_journal")]
+    vm::journal::dump_journal();
+    #[cfg(feature = "qemu_test")]
+    hw::qemu_test::qemu_exit_failure(1);
+    #[cfg(not(feature = "qemu_test"))]
     loop {}
 }
 
@@ -48,7 +61,7 @@ fn panic(info: &PanicInfo) -> ! {
 /// Run configuration steps that will allow us to run the
 /// kernel in supervisor mode.
 #[no_mangle]
-pub extern "C" fn _start() {
+pub extern "C" fn _start(hartid: usize, dtb: *const u8) {
     // xv6-riscv/kernel/start.c
     let fn_main = main as *const ();
 
@@ -64,7 +77,8 @@ pub extern "C" fn _start() {
     // our main function for later mret call.
     write_mepc(fn_main);
 
-    // Disable paging while setting up.
+    // Disable paging while setting up, after seeing which modes we have.
+    detect_satp_mode();
     write_satp(0);
 
     // Delegate trap handlers to kernel in supervisor mode.
@@ -76,14 +90,16 @@ pub extern "C" fn _start() {
     let sie = read_sie() | SIE_SEIE | SIE_STIE | SIE_SSIE;
     write_sie(sie);
 
+    // Let supervisor mode read cycle, time and instret.
+    write_mcounteren(MCOUNTEREN_CY | MCOUNTEREN_TM | MCOUNTEREN_IR);
+
     // Now give sup mode access to phys mem.
     // Check 3.7.1 of riscv priv isa manual.
-    write_pmpaddr0(0x3fffffffffffff_u64); // RTFM
-    write_pmpcfg0(0xf); // 1st 8 bits are pmp0cfg
+    hw::pmp::pmp_allow_user_dram(param::DRAM_BASE.addr(), param::dram_end().addr());
 
     // Store each hart's hartid in its tp reg for identification.
-    let hartid = read_mhartid();
-    write_tp(hartid);
+    write_tp(hartid as u64);
+    bootinfo::init(hartid, dtb);
 
     // Get interrupts from clock and set mtev handler fn.
     hw::timerinit();
@@ -92,26 +108,65 @@ pub extern "C" fn _start() {
     call_mret();
 }
 
+// Where _start's mret lands, on each hart.
+fn main() -> ! {
+    kmain(bootinfo::get(read_tp() as usize))
+}
+
 // Primary kernel bootstrap function.
 // We ensure that we only initialize kernel subsystems
 // one time by only doing so on hart0.
-fn main() -> ! {
+fn kmain(info: &BootInfo) -> ! {
     // We only bootstrap on hart0.
-    let id = read_tp();
-    if id == 0 {
+    if info.hart_id == 0 {
+        uart::uart_early_init();
+        uart::uart_early_print("Early boot on hart0...\r\n");
         uart::Uart::init();
         println!("{}", param::BANNER);
         log!(Info, "Bootstrapping on hart0...");
         trap::init();
         log!(Info, "Finished trap init...");
-        let _ = vm::init();
+        #[cfg(feature = "gdbstub")]
+        {
+            log!(Info, "Waiting for GDB on the UART...");
+            debug::gdb::breakpoint();
+        }
+        let vm::KernelMemoryLayout {
+            kernel_phys_base,
+            kernel_phys_end,
+            dram_size,
+            ..
+        } = vm::init(info.memory_start, info.memory_end, info.dtb_ptr)
+            .expect("Kernel VM init failed");
         log!(Info, "Initialized the kernel page table...");
+        log!(
+            Info,
+            "Kernel image {:#x}..{:#x}, {} MiB of DRAM...",
+            kernel_phys_base,
+            kernel_phys_end,
+            dram_size >> 20
+        );
         unsafe {
             log!(Debug, "Testing page allocation and freeing...");
             vm::test_palloc();
             log!(Debug, "Testing galloc allocation and freeing...");
             vm::test_galloc();
+            log!(Debug, "Comparing Kalloc fit policies...");
+            vm::test_fit_policy();
+            #[cfg(feature = "alloc_journal")]
+            {
+                log!(Debug, "Testing Kalloc journal replay...");
+                vm::journal::test_replay();
+            }
+            log!(Debug, "Testing kernel page table setup failure...");
+            vm::test_kpage_init_unwind();
+            log!(Debug, "Testing instruction cache flush...");
+            vm::ptable::test_icache_flush();
         }
+        #[cfg(feature = "latency")]
+        debug::latency::dump();
+        #[cfg(feature = "qemu_test")]
+        hw::qemu_test::qemu_exit_success();
     } else {
         //Interrupt other harts to init kpgtable.
         trap::init();
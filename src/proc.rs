@@ -0,0 +1,72 @@
+//! Per process kernel resources.
+pub mod kstack;
+
+use crate::hw::param::{MAX_PROCS, NHART};
+use crate::lock::ticketlock::TicketLock;
+
+/// PIDs 0 through NHART are reserved for the per hart idle processes.
+pub const FIRST_USER_PID: usize = NHART + 1;
+
+/// Which of the MAX_PROCS PIDs are in use, one bit each.
+pub struct PidBitmap {
+    bits: [u64; MAX_PROCS / 64],
+}
+
+impl PidBitmap {
+    pub const fn new() -> Self {
+        let mut bits = [0; MAX_PROCS / 64];
+        // Mark the reserved PIDs as used.
+        let mut pid = 0;
+        while pid < FIRST_USER_PID {
+            bits[pid / 64] |= 1 << (pid % 64);
+            pid += 1;
+        }
+        PidBitmap { bits }
+    }
+
+    /// Take the lowest free PID, or None if all MAX_PROCS are in use.
+    pub fn alloc_pid(&mut self) -> Option<usize> {
+        for (i, word) in self.bits.iter_mut().enumerate() {
+            if *word != u64::MAX {
+                let bit = word.trailing_ones() as usize;
+                *word |= 1 << bit;
+                return Some(i * 64 + bit);
+            }
+        }
+        None
+    }
+
+    /// Give pid back for reuse.
+    pub fn free_pid(&mut self, pid: usize) {
+        assert!(
+            (FIRST_USER_PID..MAX_PROCS).contains(&pid),
+            "Tried to free reserved or invalid pid: {}",
+            pid
+        );
+        let mask = 1 << (pid % 64);
+        assert!(
+            self.bits[pid / 64] & mask != 0,
+            "Double free of pid: {}",
+            pid
+        );
+        self.bits[pid / 64] &= !mask;
+    }
+}
+
+impl Default for PidBitmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// System wide PID allocator. Every hart creating a process goes
+/// through here, so it takes turns.
+pub static PIDS: TicketLock<PidBitmap> = TicketLock::new(PidBitmap::new());
+
+pub fn alloc_pid() -> Option<usize> {
+    PIDS.lock().alloc_pid()
+}
+
+pub fn free_pid(pid: usize) {
+    PIDS.lock().free_pid(pid)
+}
@@ -0,0 +1,62 @@
+//! Kernel stacks.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::hw::param::{MAX_PROCS, PAGE_SIZE};
+use crate::vm::palloc::Page;
+use crate::vm::{palloc, pfree, VmError};
+
+// Base addresses of the live KernelStack pages, 0 for an empty slot,
+// so the trap entry stack check can accept them.
+static KSTACKS: [AtomicUsize; MAX_PROCS] = [const { AtomicUsize::new(0) }; MAX_PROCS];
+
+/// Is sp on one of the allocated process kernel stacks?
+pub fn is_kernel_stack(sp: usize) -> bool {
+    KSTACKS.iter().any(|base| {
+        let base = base.load(Ordering::Acquire);
+        base != 0 && base < sp && sp <= base + PAGE_SIZE
+    })
+}
+
+/// A one page kernel stack. The page goes back to the page pool when
+/// the stack is dropped, including on error paths during setup.
+pub struct KernelStack {
+    page: Page,
+    top: *mut usize,
+}
+
+impl KernelStack {
+    /// Fails if there is no free page or MAX_PROCS stacks already exist.
+    pub fn new() -> Result<Self, VmError> {
+        let page = palloc()?;
+        let base = page.addr.addr();
+        let registered = KSTACKS.iter().any(|slot| {
+            slot.compare_exchange(0, base, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        });
+        if !registered {
+            let _ = pfree(page);
+            return Err(VmError::OutOfPages);
+        }
+        // Stacks grow down, start just past the end of the page.
+        let top = page.addr.map_addr(|addr| addr + PAGE_SIZE);
+        Ok(KernelStack { page, top })
+    }
+
+    /// Initial stack pointer for this stack.
+    pub fn top(&self) -> *mut usize {
+        self.top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let base = self.page.addr.addr();
+        if let Some(slot) = KSTACKS
+            .iter()
+            .find(|slot| slot.load(Ordering::Acquire) == base)
+        {
+            slot.store(0, Ordering::Release);
+        }
+        let _ = pfree(self.page);
+    }
+}
@@ -0,0 +1,2 @@
+//! Synchronization primitives that aren't locks.
+pub mod atomic;
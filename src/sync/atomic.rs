@@ -0,0 +1,38 @@
+//! Shorthands for atomic statistics counters.
+// Counters that are only ever read for reporting don't order any other
+// memory access, so Relaxed is enough. Anything that publishes data to
+// another hart needs Release/Acquire and should use core::sync::atomic
+// directly.
+pub use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// A counter bumped with Relaxed ordering.
+pub trait RelaxedCounter {
+    type Value;
+
+    /// Add `n`, returning the previous value.
+    fn fetch_add_relaxed(&self, n: Self::Value) -> Self::Value;
+
+    fn load_relaxed(&self) -> Self::Value;
+}
+
+macro_rules! relaxed_counter {
+    ($atomic:ty, $value:ty) => {
+        impl RelaxedCounter for $atomic {
+            type Value = $value;
+
+            #[inline(always)]
+            fn fetch_add_relaxed(&self, n: $value) -> $value {
+                self.fetch_add(n, Ordering::Relaxed)
+            }
+
+            #[inline(always)]
+            fn load_relaxed(&self) -> $value {
+                self.load(Ordering::Relaxed)
+            }
+        }
+    };
+}
+
+relaxed_counter!(AtomicU32, u32);
+relaxed_counter!(AtomicU64, u64);
+relaxed_counter!(AtomicUsize, usize);
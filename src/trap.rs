@@ -1,6 +1,9 @@
 //! Kernel trap handlers.
 use crate::device::clint;
+use crate::hw::param::{is_valid_kstack, rodata_end, text_end};
 use crate::hw::riscv;
+use crate::ipc::ipi_ring;
+use crate::proc::kstack;
 use crate::vm::ptable::PageTable;
 
 use crate::log;
@@ -27,15 +30,33 @@ pub fn init() {
     riscv::write_stvec(__strapvec as usize);
 }
 
+/// Called from both trap vectors with the interrupted stack pointer,
+/// before the real handler runs. A sp outside the kernel stacks means
+/// the stack is already corrupted, so halt instead of carrying on.
+#[no_mangle]
+pub extern "C" fn check_kstack(sp: usize) {
+    if !is_valid_kstack(sp) && !kstack::is_kernel_stack(sp) {
+        log::log!(Error, "Trap taken with bad kernel sp: 0x{:x}", sp);
+        panic!("Corrupted kernel stack pointer");
+    }
+}
+
 /// Machine mode trap handler.
 #[no_mangle]
 pub extern "C" fn m_handler() {
     let mcause = riscv::read_mcause();
+    #[cfg(feature = "latency")]
+    let entered = crate::debug::latency::trap_enter();
 
     match mcause {
         riscv::MSTATUS_TIMER => {
             // log::log!(Debug, "Machine timer interupt, hart: {}", riscv::read_mhartid());
-            clint::set_mtimecmp(10_000_000);
+            clint::schedule_next_tick();
+        }
+        riscv::MCAUSE_SOFT => {
+            // Only supervisor mode handles IPIs, pass it down.
+            clint::clear_msip(riscv::read_mhartid() as usize);
+            riscv::write_mip(riscv::read_mip() | riscv::MIP_SSIP);
         }
         _ => {
             log::log!(
@@ -46,19 +67,48 @@ pub extern "C" fn m_handler() {
             panic!();
         }
     }
+    #[cfg(feature = "latency")]
+    crate::debug::latency::trap_exit(mcause, entered);
 }
 
+/// General purpose registers x0-x31 as saved on the supervisor scratch
+/// stack by __strapvec. The x2 slot holds the scratch sp, the interrupted
+/// sp is in sscratch.
+pub type TrapRegs = [usize; 32];
+
 /// Supervisor mode trap handler.
 #[no_mangle]
-pub extern "C" fn s_handler() {
+#[cfg_attr(not(feature = "gdbstub"), allow(unused_variables))]
+pub extern "C" fn s_handler(regs: &mut TrapRegs) {
     let cause = riscv::read_scause();
+    #[cfg(feature = "latency")]
+    let entered = crate::debug::latency::trap_enter();
+
+    match cause {
+        riscv::SCAUSE_STORE_PAGE_FAULT => handle_store_fault(riscv::read_stval()),
+        #[cfg(feature = "gdbstub")]
+        riscv::SCAUSE_BREAKPOINT => crate::debug::gdb::handle_trap(regs),
+        riscv::SCAUSE_SOFT => ipi_ring::handle_ipi(),
+        _ => {
+            log::log!(
+                Warning,
+                "Uncaught supervisor mode interupt. scause: 0x{:x}",
+                cause
+            );
+            panic!()
+        }
+    }
+    #[cfg(feature = "latency")]
+    crate::debug::latency::trap_exit(cause, entered);
+}
 
-    {
-        log::log!(
-            Warning,
-            "Uncaught supervisor mode interupt. scause: 0x{:x}",
-            cause
-        );
-        panic!()
+/// Store page fault. A store into the kernel's .rodata is always a kernel
+/// bug, so halt rather than try to recover (or blame a user process).
+fn handle_store_fault(stval: usize) {
+    if (text_end().addr()..rodata_end().addr()).contains(&stval) {
+        log::log!(Error, "Kernel store to rodata at 0x{:x}", stval);
+        panic!("Store to read only kernel data");
     }
+    log::log!(Warning, "Uncaught store page fault. stval: 0x{:x}", stval);
+    panic!()
 }
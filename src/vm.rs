@@ -1,11 +1,18 @@
 //! Virtual Memory
 pub mod global;
-mod palloc;
+pub mod journal;
+pub mod magazine;
+pub mod palloc;
 pub mod process;
 pub mod ptable;
+pub mod refcount;
+pub mod types;
 pub mod vmalloc;
 
+use crate::boot::fdt::Fdt;
 use crate::hw::param::*;
+use crate::hw::riscv::satp_mode;
+use crate::lock::mutex::Mutex;
 use alloc::boxed::Box;
 use core::alloc::{GlobalAlloc, Layout};
 use core::cell::OnceCell;
@@ -13,7 +20,9 @@ use core::cell::OnceCell;
 use global::Galloc;
 use palloc::*;
 use process::Process;
-use ptable::kpage_init; //, PageTable};
+pub use ptable::protect_rodata;
+use ptable::{kpage_init, PageTable};
+use types::PhysAddr;
 
 /// Global physical page pool allocated by the kernel physical allocator.
 static mut PAGEPOOL: OnceCell<PagePool> = OnceCell::new();
@@ -53,6 +62,100 @@ pub enum VmError {
     PfreeFail,
     GNoSpace,
     Koom,
+    NoPressureSlot,
+    BadPte,
+    /// No page left for an intermediate page table, refill the reserve.
+    OutOfPageTableMemory,
+    /// Asked for a mapping that is both writable and executable.
+    WxViolation,
+    Kalloc(vmalloc::KallocError),
+}
+
+/// Something that holds on to memory it can give back when the kernel
+/// runs out, e.g. a page cache or slab cache shrinker.
+pub trait MemoryPressureHandler: Send {
+    /// Release as much memory as possible. Returns the number of bytes
+    /// reclaimed, zero if there was nothing to give back.
+    // Called from inside the allocator, so this must not allocate.
+    fn reclaim(&mut self) -> usize;
+}
+
+const MAX_PRESSURE_HANDLERS: usize = 8;
+const NO_HANDLER: Option<&'static mut dyn MemoryPressureHandler> = None;
+
+/// Handlers to call, in registration order, when an allocation hits OOM.
+pub static PRESSURE_HANDLERS: Mutex<
+    [Option<&'static mut dyn MemoryPressureHandler>; MAX_PRESSURE_HANDLERS],
+> = Mutex::new([NO_HANDLER; MAX_PRESSURE_HANDLERS]);
+
+/// Add a handler to the memory pressure registry.
+pub fn register_pressure_handler(
+    handler: &'static mut dyn MemoryPressureHandler,
+) -> Result<(), VmError> {
+    let mut handlers = PRESSURE_HANDLERS.lock();
+    match handlers.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(handler);
+            Ok(())
+        }
+        None => Err(VmError::NoPressureSlot),
+    }
+}
+
+/// Ask each registered handler in turn to reclaim memory, calling
+/// `retry` once after every handler that gave something back.
+/// Returns the first successful retry, or None if memory is still short.
+pub fn relieve_pressure<T>(mut retry: impl FnMut() -> Option<T>) -> Option<T> {
+    let mut handlers = PRESSURE_HANDLERS.lock();
+    for handler in handlers.iter_mut().flatten() {
+        if handler.reclaim() != 0 {
+            if let Some(out) = retry() {
+                return Some(out);
+            }
+        }
+    }
+    None
+}
+
+const PT_RESERVE_PAGES: usize = 8;
+
+/// Pages set aside for page table pages, so that mapping memory (e.g.
+/// from a page fault) still works when the page pool runs dry.
+struct PtPageReserve {
+    pages: [Option<Page>; PT_RESERVE_PAGES],
+}
+
+// Only reached through the PT_RESERVE mutex.
+unsafe impl Send for PtPageReserve {}
+
+impl PtPageReserve {
+    /// Top the reserve back up from the page pool, as far as it can.
+    fn refill(&mut self) {
+        for slot in self.pages.iter_mut().filter(|slot| slot.is_none()) {
+            match palloc_zeroed() {
+                Ok(page) => *slot = Some(page),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+static PT_RESERVE: Mutex<PtPageReserve> = Mutex::new(PtPageReserve {
+    pages: [None; PT_RESERVE_PAGES],
+});
+
+/// Allocate a (zeroed) page for a page table. Takes from the reserve
+/// first, then refills it from the page pool while memory lasts. Only
+/// fails, with OutOfPageTableMemory, once the reserve and the pool are
+/// both empty.
+pub fn pt_palloc() -> Result<Page, VmError> {
+    let mut reserve = PT_RESERVE.lock();
+    let page = reserve.pages.iter_mut().find_map(|slot| slot.take());
+    reserve.refill();
+    match page {
+        Some(page) => Ok(page),
+        None => palloc_zeroed().map_err(|_| VmError::OutOfPageTableMemory),
+    }
 }
 
 /// Moving to `mod process`
@@ -80,24 +183,148 @@ pub struct TaskNode {
 //     unsafe { VMALLOC.get_mut().unwrap().free(ptr) }
 // }
 
-fn palloc() -> Result<Page, VmError> {
+pub(crate) fn palloc() -> Result<Page, VmError> {
     unsafe { PAGEPOOL.get_mut().unwrap().palloc() }
 }
 
-fn pfree(page: Page) -> Result<(), VmError> {
+/// Allocate a page that is guaranteed to be all zeros. Pages that were
+/// zeroed when freed aren't zeroed a second time.
+pub(crate) fn palloc_zeroed() -> Result<Page, VmError> {
+    // palloc always hands out zeroed pages, this just makes it explicit.
+    let page = palloc()?;
+    debug_assert!(
+        (0..PAGE_SIZE / 8).all(|i| unsafe { page.addr.add(i).read() } == 0),
+        "palloc handed out a dirty page at {:?}",
+        page.addr
+    );
+    Ok(page)
+}
+
+pub(crate) fn pfree(page: Page) -> Result<(), VmError> {
     unsafe { PAGEPOOL.get_mut().unwrap().pfree(page) }
 }
 
+/// Common interface of the kernel's sub-page allocators, so the global
+/// allocator can switch between them.
+pub trait KernelAllocator {
+    /// Allocate `size` bytes aligned to `align`, a power of two.
+    fn alloc(&mut self, size: usize, align: usize) -> Result<*mut u8, VmError>;
+    /// Free `ptr`, an allocation of `size` bytes made by this allocator.
+    fn free(&mut self, ptr: *mut u8, size: usize);
+}
+
+/// A NUMA node and the physical memory close to it.
+#[derive(Copy, Clone)]
+pub struct NumaNode {
+    pub id: usize,
+    pub bottom: PhysAddr,
+    pub top: PhysAddr,
+}
+
+const MAX_NUMA_NODES: usize = 8;
+
+/// NUMA nodes by id, from the device tree. Set once by vm::init.
+static mut NUMA_NODES: OnceCell<[Option<NumaNode>; MAX_NUMA_NODES]> = OnceCell::new();
+
+/// Build the NUMA node table from the `numa-node-id` of the memory nodes
+/// in the device tree at `dtb`, limited to the page pool's memory
+/// [memory_start, memory_end). Memory nodes with the same id are merged.
+/// Without any numa-node-id, all of that memory is node 0.
+fn numa_init(dtb: *const u8, memory_start: usize, memory_end: usize) {
+    let mut nodes = [None; MAX_NUMA_NODES];
+    if let Some(fdt) = unsafe { Fdt::from_ptr(dtb) } {
+        fdt.for_each_memory(|mem| {
+            let Some(id) = mem.numa_node_id.map(|id| id as usize) else {
+                return;
+            };
+            let bottom = core::cmp::max(mem.base, memory_start);
+            let top = core::cmp::min(mem.base + mem.size, memory_end);
+            if id >= MAX_NUMA_NODES || bottom >= top {
+                return;
+            }
+            let node: &mut Option<NumaNode> = &mut nodes[id];
+            *node = Some(match *node {
+                Some(n) => NumaNode {
+                    id,
+                    bottom: core::cmp::min(n.bottom, PhysAddr::new(bottom)),
+                    top: core::cmp::max(n.top, PhysAddr::new(top)),
+                },
+                None => NumaNode {
+                    id,
+                    bottom: PhysAddr::new(bottom),
+                    top: PhysAddr::new(top),
+                },
+            });
+        });
+    }
+    if nodes.iter().all(Option::is_none) {
+        nodes[0] = Some(NumaNode {
+            id: 0,
+            bottom: PhysAddr::new(memory_start),
+            top: PhysAddr::new(memory_end),
+        });
+    }
+    unsafe {
+        if (*core::ptr::addr_of_mut!(NUMA_NODES)).set(nodes).is_err() {
+            panic!("NUMA nodes double init.")
+        }
+    }
+}
+
+/// Look up NUMA node `id`.
+pub fn numa_node(id: usize) -> Option<NumaNode> {
+    let nodes = unsafe { (*core::ptr::addr_of!(NUMA_NODES)).get()? };
+    *nodes.get(id)?
+}
+
+/// Allocate a page from `preferred_node`'s memory if it has one free,
+/// otherwise from anywhere.
+pub(crate) fn palloc_numa(preferred_node: usize) -> Result<Page, VmError> {
+    if let Some(node) = numa_node(preferred_node) {
+        let pool = unsafe { PAGEPOOL.get_mut().unwrap() };
+        if let Ok(page) = pool.palloc_range(node.bottom, node.top) {
+            return Ok(page);
+        }
+    }
+    palloc()
+}
+
+/// Allocate a page from physical memory in [bottom, top).
+pub(crate) fn palloc_range(bottom: PhysAddr, top: PhysAddr) -> Result<Page, VmError> {
+    unsafe { PAGEPOOL.get_mut().unwrap().palloc_range(bottom, top) }
+}
+
+/// What vm::init set up. The kernel allocator isn't part of it, it
+/// belongs to the global allocator.
+#[derive(Copy, Clone)]
+pub struct KernelMemoryLayout {
+    /// The kernel page table, already installed in satp.
+    pub page_table: PageTable,
+    /// First byte of the kernel image.
+    pub kernel_phys_base: usize,
+    /// End of the kernel image (.bss), where the page pool starts.
+    pub kernel_phys_end: usize,
+    pub dram_size: usize,
+}
+
 /// Initialize the kernel VM system.
-/// First, setup the kernel physical page pool.
-/// We start the pool at the end of the .bss section, and stop at the end of physical memory.
+/// First, read the NUMA nodes from the device tree at `dtb` (may be null).
+/// Then setup the kernel physical page pool over [memory_start, memory_end),
+/// from the end of the .bss section to the end of physical memory.
 /// Next, we map physical memory into the kernel's physical memory 1:1.
 /// Next, initialize the kernel virtual memory allocator pool.
 /// Finally we set the global kernel page table `KPGTABLE` variable to point to the
 /// kernel's page table struct.
-pub fn init() -> Result<(), PagePool> {
+pub fn init(
+    memory_start: usize,
+    memory_end: usize,
+    dtb: *const u8,
+) -> Result<KernelMemoryLayout, VmError> {
+    // The device tree is in the memory the page pool is about to take.
+    numa_init(dtb, memory_start, memory_end);
     unsafe {
-        match PAGEPOOL.set(PagePool::new(bss_end(), dram_end())) {
+        let (bottom, top) = (PhysAddr::new(memory_start), PhysAddr::new(memory_end));
+        match PAGEPOOL.set(PagePool::new(bottom, top)) {
             Ok(_) => {}
             Err(_) => {
                 panic!("vm double init.")
@@ -106,6 +333,24 @@ pub fn init() -> Result<(), PagePool> {
     }
     log!(Debug, "Successfully initialized kernel page pool...");
 
+    unsafe {
+        let end = PhysAddr::new(memory_end);
+        let pages = refcount::PageRefCount::table_size(end) / PAGE_SIZE;
+        let table = PAGEPOOL
+            .get_mut()
+            .unwrap()
+            .palloc_plural(pages)
+            .expect("No memory for page refcounts");
+        let first_free = PhysAddr::new(memory_start);
+        refcount::init(refcount::PageRefCount::new(table, end, first_free));
+    }
+    log!(Debug, "Successfully initialized page refcounts...");
+
+    #[cfg(feature = "memtest")]
+    unsafe {
+        crate::boot::memtest::memtest((*core::ptr::addr_of!(PAGEPOOL)).get().unwrap());
+    }
+
     unsafe {
         match GLOBAL.inner.set(Galloc::new(PAGEPOOL.get_mut().unwrap())) {
             Ok(_) => {}
@@ -115,14 +360,26 @@ pub fn init() -> Result<(), PagePool> {
         }
     }
 
+    PT_RESERVE.lock().refill();
+
+    // Sv48 hardware must support Sv39 too.
+    let mode = satp_mode();
+    assert!(
+        mode >= ptable::PAGING_MODE,
+        "Hart can't do {:?} paging, only {:?}",
+        ptable::PAGING_MODE,
+        mode
+    );
+
     // Map text, data, stacks, heap into kernel page table.
-    match kpage_init() {
-        Ok(pt) => pt.write_satp(),
-        Err(_) => {
-            panic!();
-        }
-    }
-    Ok(())
+    let page_table = kpage_init()?;
+    page_table.write_satp();
+    Ok(KernelMemoryLayout {
+        page_table,
+        kernel_phys_base: DRAM_BASE.addr(),
+        kernel_phys_end: bss_end().addr(),
+        dram_size: memory_end - DRAM_BASE.addr(),
+    })
 }
 
 /// A test designed to be used with GDB.
@@ -140,6 +397,65 @@ pub unsafe fn test_palloc() {
     log!(Debug, "Successful test of page allocation and freeing...");
 }
 
+/// Make kpage_init run out of page table pages part way through, and
+/// check that it fails and gives back every page it took.
+/// # Safety
+/// Only call at boot, after vm::init, while nothing else allocates.
+pub unsafe fn test_kpage_init_unwind() {
+    // Pages in the pool plus those set aside in PT_RESERVE.
+    fn free_pages() -> usize {
+        let reserve = PT_RESERVE.lock();
+        let reserved = reserve.pages.iter().filter(|p| p.is_some()).count();
+        let pool = unsafe { (*core::ptr::addr_of!(PAGEPOOL)).get().unwrap() };
+        pool.stats().free_pages + reserved
+    }
+    let pool = (*core::ptr::addr_of_mut!(PAGEPOOL)).get_mut().unwrap();
+    let before = free_pages();
+
+    // Empty the pool, chaining the pages through their first word.
+    let mut drained: *mut usize = core::ptr::null_mut();
+    let mut take = |page: Page| {
+        page.addr.write(drained.addr());
+        drained = page.addr;
+    };
+    while let Ok(page) = pool.palloc() {
+        take(page);
+    }
+    {
+        let mut reserve = PT_RESERVE.lock();
+        for slot in reserve.pages.iter_mut() {
+            if let Some(page) = slot.take() {
+                take(page);
+            }
+        }
+        // The root and the tables for the UART. The CLINT needs one more.
+        for slot in reserve.pages.iter_mut().take(3) {
+            let page = Page::from(drained);
+            drained = drained.read() as *mut usize;
+            page.addr.write(0);
+            *slot = Some(page);
+        }
+    }
+    let drained_free = free_pages();
+
+    assert!(kpage_init().is_err(), "kpage_init with no memory succeeded");
+    assert_eq!(
+        free_pages(),
+        drained_free,
+        "kpage_init leaked page table pages"
+    );
+
+    while !drained.is_null() {
+        let next = drained.read() as *mut usize;
+        let _ = pool.pfree(Page::from(drained));
+        drained = next;
+    }
+    PT_RESERVE.lock().refill();
+    assert_eq!(free_pages(), before);
+
+    log!(Debug, "Successful test of kpage_init unwinding...");
+}
+
 pub unsafe fn test_galloc() {
     use alloc::collections;
     {
@@ -165,3 +481,49 @@ pub unsafe fn test_galloc() {
 
     log!(Debug, "Successful test of alloc crate...");
 }
+
+/// Run the same 10,000 mixed size allocations and frees on a fresh Kalloc
+/// under each fit policy, and log how fragmented each pool ends up.
+pub fn test_fit_policy() {
+    use alloc::vec::Vec;
+    use vmalloc::{FitPolicy, Kalloc};
+
+    for policy in [FitPolicy::FirstFit, FitPolicy::BestFit] {
+        let page = palloc().expect("No page for the test pool");
+        let mut pool = Kalloc::new(page);
+        pool.set_fit_policy(policy);
+        let mut live: Vec<*mut usize> = Vec::new();
+        // Fixed seed LCG, so both policies see the same sequence.
+        let mut seed: u64 = 0x5eed;
+        let mut next = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) as usize
+        };
+
+        for _ in 0..10_000 {
+            let r = next();
+            if !live.is_empty() && r % 3 == 0 {
+                let ptr = live.swap_remove(next() % live.len());
+                pool.free(ptr).expect("Test pool free failed");
+            } else {
+                let size = 8 + r % 512;
+                live.push(pool.alloc(size).expect("Test pool alloc failed"));
+            }
+        }
+        let stats = pool.stats();
+        log!(
+            Info,
+            "{:?}: {} bytes live, {} free, {}% fragmented...",
+            policy,
+            stats.used_bytes,
+            stats.free_bytes,
+            stats.fragmentation_ratio()
+        );
+
+        for ptr in live {
+            pool.free(ptr).expect("Test pool free failed");
+        }
+        // Every other zone went back to the pool as it emptied.
+        let _ = pfree(page);
+    }
+}
@@ -1,13 +1,16 @@
 use crate::param::PAGE_SIZE;
 use crate::vm::palloc::PagePool;
 use crate::vm::vmalloc::{Kalloc, MAX_CHUNK_SIZE};
+use crate::vm::KernelAllocator;
 /// Global allocator on top of vmalloc and palloc
 use core::alloc::{GlobalAlloc, Layout};
 use core::cell::UnsafeCell;
 
 pub struct Galloc {
     pool: *mut PagePool,
-    small_pool: UnsafeCell<Kalloc>,
+    boot_pool: UnsafeCell<Kalloc>,
+    // Sub-page allocator in use, the boot pool if None.
+    small_pool: UnsafeCell<Option<&'static mut dyn KernelAllocator>>,
 }
 
 impl Galloc {
@@ -17,9 +20,26 @@ impl Galloc {
             .expect("Could not initalize GlobalAlloc small pool");
         Galloc {
             pool,
-            small_pool: UnsafeCell::new(Kalloc::new(small_pool_start)),
+            boot_pool: UnsafeCell::new(Kalloc::new(small_pool_start)),
+            small_pool: UnsafeCell::new(None),
         }
     }
+
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn small_pool(&self) -> &mut dyn KernelAllocator {
+        match &mut *self.small_pool.get() {
+            Some(alloc) => *alloc,
+            None => &mut *self.boot_pool.get(),
+        }
+    }
+
+    /// Serve sub-page allocations from `alloc` from now on.
+    /// # Safety
+    /// Memory already allocated from the previous small pool must never be
+    /// freed through the global allocator, it would go to `alloc`.
+    pub unsafe fn replace_small_pool(&self, alloc: &'static mut dyn KernelAllocator) {
+        *self.small_pool.get() = Some(alloc);
+    }
 }
 
 impl Drop for Galloc {
@@ -59,8 +79,8 @@ unsafe impl GlobalAlloc for Galloc {
         let num_pages = decide_internal_scheme(layout);
 
         if num_pages == 0 {
-            match (*self.small_pool.get()).alloc(layout.size()) {
-                Ok(ptr) => ptr as *mut u8,
+            match self.small_pool().alloc(layout.size(), layout.align()) {
+                Ok(ptr) => ptr,
                 Err(e) => {
                     panic!("Small allocation failed {:?}", e)
                 }
@@ -83,7 +103,7 @@ unsafe impl GlobalAlloc for Galloc {
         let num_pages = decide_internal_scheme(layout);
 
         if num_pages == 0 {
-            (*self.small_pool.get()).free(ptr as *mut usize)
+            self.small_pool().free(ptr, layout.size())
         } else {
             match (*self.pool).pfree_plural(ptr as *mut usize, num_pages) {
                 Ok(_) => {}
@@ -0,0 +1,185 @@
+//! Record of recent Kalloc operations, to reproduce allocator bugs.
+// With the `alloc_journal` feature every Kalloc::alloc and Kalloc::free
+// lands in JOURNAL, a ring of the last JOURNAL_SIZE operations. The panic
+// handler dumps it, and `replay` runs a copy of it against another pool.
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use super::vmalloc::Kalloc;
+use crate::device::clint::read_mtime;
+use crate::device::uart::EarlyUart;
+use crate::lock::mutex::Mutex;
+
+pub const JOURNAL_SIZE: usize = 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AllocOp {
+    Alloc,
+    Free,
+}
+
+#[derive(Copy, Clone)]
+pub struct JournalEntry {
+    pub op: AllocOp,
+    pub ptr: usize,
+    pub size: usize,
+    pub tick: u64,
+}
+
+#[derive(Clone)]
+pub struct AllocJournal {
+    entries: [JournalEntry; JOURNAL_SIZE],
+    idx: usize, // Operations ever recorded, the next goes at idx % JOURNAL_SIZE.
+}
+
+pub static JOURNAL: Mutex<AllocJournal> = Mutex::new(AllocJournal::new());
+
+impl AllocJournal {
+    pub const fn new() -> Self {
+        AllocJournal {
+            entries: [JournalEntry {
+                op: AllocOp::Alloc,
+                ptr: 0,
+                size: 0,
+                tick: 0,
+            }; JOURNAL_SIZE],
+            idx: 0,
+        }
+    }
+
+    fn push(&mut self, entry: JournalEntry) {
+        self.entries[self.idx % JOURNAL_SIZE] = entry;
+        self.idx += 1;
+    }
+
+    /// Forget every recorded operation.
+    pub fn clear(&mut self) {
+        self.idx = 0;
+    }
+
+    fn len(&self) -> usize {
+        core::cmp::min(self.idx, JOURNAL_SIZE)
+    }
+
+    /// The recorded entries, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &JournalEntry> + '_ {
+        let first = self.idx - self.len();
+        (first..self.idx).map(move |i| &self.entries[i % JOURNAL_SIZE])
+    }
+
+    /// Print the journal to the UART, newest first. Takes no locks, so
+    /// the panic handler can use it.
+    pub fn dump(&self) {
+        let mut out = EarlyUart;
+        let _ = write!(
+            out,
+            "Kalloc journal, last {} of {} ops:\r\n",
+            self.len(),
+            self.idx
+        );
+        for e in self.iter().rev() {
+            let _ = write!(out, "{:>12} {:?} {:#x} {}\r\n", e.tick, e.op, e.ptr, e.size);
+        }
+    }
+
+    /// Redo the journal's operations on `kalloc`. Frees go to whatever
+    /// the replayed alloc of the same pointer returned; frees of chunks
+    /// allocated before the journal starts are skipped. Takes a copy, not
+    /// JOURNAL itself, which replaying records into.
+    pub fn replay(j: &AllocJournal, kalloc: &mut Kalloc) {
+        // Replayed address of each journal entry's allocation.
+        let mut replayed: Vec<Option<*mut usize>> = Vec::with_capacity(j.len());
+        for (i, e) in j.iter().enumerate() {
+            match e.op {
+                AllocOp::Alloc => replayed.push(kalloc.alloc(e.size).ok()),
+                AllocOp::Free => {
+                    replayed.push(None);
+                    let alloc = j
+                        .iter()
+                        .take(i)
+                        .enumerate()
+                        .filter(|(_, a)| a.op == AllocOp::Alloc && a.ptr == e.ptr)
+                        .last();
+                    if let Some((a, _)) = alloc {
+                        if let Some(ptr) = replayed[a].take() {
+                            let _ = kalloc.free(ptr);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for AllocJournal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Record one operation, if the `alloc_journal` feature is on.
+pub fn record(op: AllocOp, ptr: *mut usize, size: usize) {
+    if cfg!(feature = "alloc_journal") {
+        JOURNAL.lock().push(JournalEntry {
+            op,
+            ptr: ptr.addr(),
+            size,
+            tick: read_mtime(),
+        });
+    }
+}
+
+/// Run a fixed sequence of allocs and frees on a fresh Kalloc, replay
+/// the journal of it on a second one, and check both pools peaked at the
+/// same usage and ended empty. Run at boot, after vm::init, with the
+/// `alloc_journal` feature on.
+pub fn test_replay() {
+    use super::{palloc, pfree};
+    use alloc::boxed::Box;
+
+    let (first, second) = (
+        palloc().expect("No page for the test pool"),
+        palloc().expect("No page for the test pool"),
+    );
+    let mut original = Kalloc::new(first);
+    JOURNAL.lock().clear();
+
+    let mut ptrs = [core::ptr::null_mut::<usize>(); 48];
+    for (i, ptr) in ptrs.iter_mut().enumerate() {
+        *ptr = original.alloc(8 + i * 40).expect("Test pool alloc failed");
+    }
+    for ptr in ptrs.iter().step_by(2) {
+        original.free(*ptr).expect("Test pool free failed");
+    }
+    for ptr in ptrs.iter_mut().step_by(2) {
+        *ptr = original.alloc(100).expect("Test pool alloc failed");
+    }
+    for ptr in ptrs {
+        original.free(ptr).expect("Test pool free failed");
+    }
+
+    // Too big for this stack, and replay records into JOURNAL itself.
+    let mut copy: Box<core::mem::MaybeUninit<AllocJournal>> = Box::new_uninit();
+    let copy = unsafe {
+        copy.as_mut_ptr()
+            .copy_from_nonoverlapping(JOURNAL.as_ptr(), 1);
+        copy.assume_init()
+    };
+    let mut replayed = Kalloc::new(second);
+    AllocJournal::replay(&copy, &mut replayed);
+
+    let (a, b) = (original.stats(), replayed.stats());
+    assert_eq!(b.used_bytes, 0, "Replay left allocations behind");
+    assert_eq!(
+        a.high_water_used_bytes, b.high_water_used_bytes,
+        "Replay didn't reproduce the pool's peak usage"
+    );
+    let _ = pfree(first);
+    let _ = pfree(second);
+    log!(Debug, "Successful test of Kalloc journal replay...");
+}
+
+/// Dump JOURNAL without taking its lock, for the panic handler.
+pub fn dump_journal() {
+    unsafe { &*JOURNAL.as_ptr() }.dump();
+}
@@ -0,0 +1,131 @@
+//! Per hart magazine cache in front of a shared `Kalloc`.
+// Bonwick and Adams, "Magazines and Vmem" (USENIX 2001), without the
+// depot of spare magazines: each hart keeps a loaded and a previous
+// magazine of free chunks of one size, and only takes the Kalloc lock to
+// move a whole magazine's worth at a time.
+use core::cell::UnsafeCell;
+use core::mem::swap;
+
+use super::vmalloc::{Kalloc, KallocError};
+use crate::hw::param::NHART;
+use crate::hw::riscv::{read_tp, InterruptGuard};
+use crate::lock::mutex::Mutex;
+
+/// Chunks held by one magazine.
+pub const MAGAZINE_SIZE: usize = 16;
+
+/// A stack of free chunks.
+struct Magazine {
+    rounds: [*mut usize; MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Magazine {
+            rounds: [core::ptr::null_mut(); MAGAZINE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<*mut usize> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.rounds[self.len])
+    }
+
+    fn push(&mut self, ptr: *mut usize) -> Result<(), *mut usize> {
+        if self.len == MAGAZINE_SIZE {
+            return Err(ptr);
+        }
+        self.rounds[self.len] = ptr;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+struct HartMagazines {
+    loaded: Magazine,
+    prev: Magazine,
+}
+
+/// Caches chunks of `size` bytes from `depot` per hart.
+pub struct MagazineCache {
+    size: usize,
+    depot: &'static Mutex<Kalloc>,
+    harts: [UnsafeCell<HartMagazines>; NHART],
+}
+
+// Each hart only touches its own magazines, with interrupts off.
+unsafe impl Sync for MagazineCache {}
+
+impl MagazineCache {
+    pub const fn new(size: usize, depot: &'static Mutex<Kalloc>) -> Self {
+        MagazineCache {
+            size,
+            depot,
+            harts: [const {
+                UnsafeCell::new(HartMagazines {
+                    loaded: Magazine::new(),
+                    prev: Magazine::new(),
+                })
+            }; NHART],
+        }
+    }
+
+    /// Run `f` on this hart's magazines. Interrupts stay off so a trap
+    /// on this hart can't use them at the same time.
+    fn with_magazines<R>(&self, f: impl FnOnce(&mut HartMagazines) -> R) -> R {
+        let _guard = InterruptGuard::new();
+        let hart = read_tp() as usize;
+        f(unsafe { &mut *self.harts[hart].get() })
+    }
+
+    /// Allocate a chunk of the cache's size.
+    pub fn alloc(&self) -> Result<*mut usize, KallocError> {
+        self.with_magazines(|mags| {
+            if let Some(ptr) = mags.loaded.pop() {
+                return Ok(ptr);
+            }
+            swap(&mut mags.loaded, &mut mags.prev);
+            if let Some(ptr) = mags.loaded.pop() {
+                return Ok(ptr);
+            }
+            // Both empty, refill the loaded magazine in one go.
+            let mut depot = self.depot.lock();
+            for _ in 0..MAGAZINE_SIZE {
+                match depot.alloc(self.size) {
+                    Ok(ptr) => {
+                        let _ = mags.loaded.push(ptr);
+                    }
+                    Err(e) if mags.loaded.len == 0 => return Err(e),
+                    Err(_) => break,
+                }
+            }
+            Ok(mags.loaded.pop().unwrap())
+        })
+    }
+
+    /// Free a chunk allocated from this cache.
+    pub fn free(&self, ptr: *mut usize) {
+        self.with_magazines(|mags| {
+            let Err(ptr) = mags.loaded.push(ptr) else {
+                return;
+            };
+            swap(&mut mags.loaded, &mut mags.prev);
+            let Err(ptr) = mags.loaded.push(ptr) else {
+                return;
+            };
+            // Both full, hand a magazine's worth back.
+            let mut depot = self.depot.lock();
+            while let Some(chunk) = mags.loaded.pop() {
+                if let Err(e) = depot.free(chunk) {
+                    panic!("Magazine free failed {:?}", e)
+                }
+            }
+            let _ = mags.loaded.push(ptr);
+        })
+    }
+}
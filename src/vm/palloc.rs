@@ -1,8 +1,14 @@
 //! Physical page allocator
 use crate::hw::param::*;
+use crate::hw::riscv::fence_rw;
 use crate::lock::mutex::Mutex;
+use crate::vm::types::{PhysAddr, VirtAddr};
 use crate::vm::VmError;
 
+/// Third word of a free page that `free_pages` zeroed, everything but the
+/// free list links is still zero.
+const ZEROED: usize = 0x2E70_2E70_2E70_2E70;
+
 /// Utility function, primarily used to check if addresses are page aligned.
 fn is_multiple(addr: usize, size: usize) -> bool {
     addr & (size - 1) == 0
@@ -18,6 +24,18 @@ struct Pool {
     free: Option<Page>, // Head of free page list (stored in the free pages).
     bottom: *mut usize, // Min addr of this page allocation pool.
     top: *mut usize,    // Max addr of this page allocation pool.
+    used: usize,        // Pages currently allocated.
+    reserved: usize,    // Pages taken off the free list by retain_free.
+    high_water_used: usize,
+}
+
+/// Snapshot of a `PagePool`'s usage, in pages.
+#[derive(Copy, Clone, Debug)]
+pub struct PagePoolStats {
+    pub total_pages: usize,
+    pub free_pages: usize,
+    pub reserved_pages: usize,
+    pub high_water_used: usize,
 }
 
 /// Convenience struct to read a free page like a doubly linked list.
@@ -27,6 +45,8 @@ struct FreeNode {
 }
 
 /// Abstraction of a physical page of memory.
+/// `addr` is the kernel's (1:1 mapped) pointer to the page, use
+/// `phys_addr` where the physical address itself is wanted.
 // TODO: Add methods to manipulate this address without pub addr field.
 #[derive(Copy, Clone)]
 #[repr(transparent)]
@@ -54,6 +74,12 @@ impl PagePool {
         }
     }
 
+    /// Allocate the lowest free page in [bottom, top).
+    pub fn palloc_range(&mut self, bottom: PhysAddr, top: PhysAddr) -> Result<Page, VmError> {
+        let mut pool = self.pool.lock();
+        pool.alloc_in_range(bottom, top).ok_or(VmError::OutOfPages)
+    }
+
     /// Free a page of physical memory by inserting into the doubly
     /// linked free list in order.
     pub fn pfree(&mut self, page: Page) -> Result<(), VmError> {
@@ -89,6 +115,82 @@ impl PagePool {
         pool.free_pages(Page::from(page), num_pages);
         Ok(())
     }
+
+    /// Call `keep` on every free page, removing the pages it returns
+    /// false for from the free list for good. `keep` may scribble on the
+    /// page but must leave it zeroed. Returns the number of pages removed.
+    pub fn retain_free(&self, mut keep: impl FnMut(*mut usize) -> bool) -> usize {
+        let mut pool = self.pool.lock();
+        let mut removed = 0;
+        let mut last_kept: Option<Page> = None;
+        let mut curr = pool.free;
+        pool.free = None;
+
+        while let Some(mut page) = curr {
+            // Read the link first, keep() may overwrite it.
+            let (_, next) = page.read_free();
+            if keep(page.addr) {
+                match last_kept {
+                    None => {
+                        page.write_prev(core::ptr::null_mut());
+                        pool.free = Some(page);
+                    }
+                    Some(mut prev) => {
+                        page.write_prev(prev.addr);
+                        prev.write_next(page.addr);
+                    }
+                }
+                last_kept = Some(page);
+            } else {
+                removed += 1;
+            }
+            curr = if next.is_null() {
+                None
+            } else {
+                Some(Page::from(next))
+            };
+        }
+        if let Some(mut last) = last_kept {
+            last.write_next(core::ptr::null_mut());
+        }
+        pool.reserved += removed;
+        removed
+    }
+
+    pub fn stats(&self) -> PagePoolStats {
+        let pool = self.pool.lock();
+        let total_pages = (pool.top.addr() - pool.bottom.addr()) / PAGE_SIZE;
+        PagePoolStats {
+            total_pages,
+            free_pages: total_pages - pool.used - pool.reserved,
+            reserved_pages: pool.reserved,
+            high_water_used: pool.high_water_used,
+        }
+    }
+
+    /// Restart the high-water mark from the current usage.
+    pub fn reset_watermark(&self) {
+        let mut pool = self.pool.lock();
+        pool.high_water_used = pool.used;
+    }
+
+    /// Scan the free list for runs of physically contiguous free pages.
+    /// Physical pages can't be moved, so this is an analysis pass: it
+    /// returns the number of contiguous runs found and logs the longest
+    /// one, which bounds the largest `palloc_plural` that can succeed.
+    // The free list is already kept in address order by `free_pages`,
+    // so there is nothing to compact here.
+    pub fn defragment(&mut self) -> usize {
+        let mut pool = self.pool.lock();
+        let (runs, longest) = pool.free_runs();
+        log!(
+            Debug,
+            "Page pool has {} free runs, longest is {} pages...",
+            runs,
+            longest
+        );
+        runs
+    }
 }
 
 /// Create a new page from a physical address.
@@ -99,6 +201,11 @@ impl From<*mut usize> for Page {
 }
 
 impl Page {
+    /// Physical address of the first byte of this page.
+    pub fn phys_addr(&self) -> PhysAddr {
+        PhysAddr::new(self.addr.addr() - crate::vm::types::KERNEL_MMU_OFFSET)
+    }
+
     /// Create a new page from a physical address.
     /// Zero the addr + 4096 bytes before returning.
     // Watchout, this zeroes new pages.
@@ -119,6 +226,25 @@ impl Page {
         }
     }
 
+    /// Mark a free page as known zero, or not.
+    fn set_zeroed(&mut self, zeroed: bool) {
+        unsafe {
+            self.addr.add(2).write(if zeroed { ZEROED } else { 0 });
+        }
+    }
+
+    /// Zero a page coming off the free list. Pages `free_pages` already
+    /// zeroed only need their links and mark cleared.
+    fn zero_for_alloc(&mut self) {
+        if unsafe { self.addr.add(2).read() } == ZEROED {
+            unsafe {
+                self.addr.write_bytes(0, 3);
+            }
+        } else {
+            self.zero();
+        }
+    }
+
     /// Write pointers to the previous and next pointers of the doubly
     /// linked list to this page. We use the first 8 bytes of the page to
     /// store a ptr to the previous page, and the second 8 bytes to
@@ -170,6 +296,8 @@ impl Pool {
         let mut pa = bottom.map_addr(|addr| addr + chunk_size);
         //let tmp = FreeNode::new(0x0 as *mut usize, pa); // First free page 'prev' == 0x0 => none.
         free.write_free(core::ptr::null_mut::<usize>(), pa);
+        // Page::new zeroed it, zero_for_alloc only needs to clear the links.
+        free.set_zeroed(true);
         let last = top.map_addr(|addr| addr - chunk_size);
         // Init the remainder of the free list.
         while pa < top {
@@ -183,6 +311,7 @@ impl Pool {
 
             let mut tmp = Page::new(pa);
             tmp.write_free(prev_pa, next_pa);
+            tmp.set_zeroed(true);
             pa = pa.map_addr(|addr| addr + chunk_size); // Don't use next_pa. End of loop will fail.
         }
 
@@ -190,6 +319,9 @@ impl Pool {
             free: Some(free),
             bottom,
             top,
+            used: 0,
+            reserved: 0,
+            high_water_used: 0,
         }
     }
 
@@ -205,13 +337,13 @@ impl Pool {
         // ^ the first page of a contigous free region, we will take
         // start_region through page (inclusive) on success
 
-        while (page.addr.map_addr(|addr| addr - start_region.addr.addr())).addr() / 0x1000
+        while (page.addr.map_addr(|addr| addr - start_region.addr.addr())).addr() / PAGE_SIZE
             < num_pages - 1
         {
             // until it's big enough
 
-            while next as usize == page.addr as usize + 0x1000
-                && (page.addr as usize - start_region.addr as usize) / 0x1000 < num_pages - 1
+            while next as usize == page.addr as usize + PAGE_SIZE
+                && (page.addr as usize - start_region.addr as usize) / PAGE_SIZE < num_pages - 1
             {
                 // until its big enough or there was a gap
                 page = Page::from(next);
@@ -222,7 +354,7 @@ impl Pool {
                 }
             }
 
-            if next as usize != page.addr as usize + 0x1000 {
+            if next as usize != page.addr as usize + PAGE_SIZE {
                 // too short!
                 start_region = Page::from(next);
             }
@@ -253,24 +385,107 @@ impl Pool {
         // zero them all out
         let mut cur = start_region;
         while cur.addr as usize <= page.addr as usize {
-            cur.zero();
-            cur = Page::from(cur.addr.map_addr(|addr| addr + 0x1000));
+            cur.zero_for_alloc();
+            cur = Page::from(cur.addr.map_addr(|addr| addr + PAGE_SIZE));
+        }
+        // The pages may become page tables (read by the MMU's walker) or be
+        // handed to another hart, so the zeroing must land first.
+        unsafe {
+            fence_rw();
         }
 
+        self.used += num_pages;
+        if self.used > self.high_water_used {
+            self.high_water_used = self.used;
+        }
         Ok(start_region)
     }
 
+    // Unlink and zero the first free page inside [bottom, top).
+    fn alloc_in_range(&mut self, bottom: PhysAddr, top: PhysAddr) -> Option<Page> {
+        let mut curr = self.free;
+        while let Some(mut page) = curr {
+            let (prev, next) = page.read_free();
+            let pa = page.phys_addr();
+            if pa >= top {
+                // The free list is in address order.
+                return None;
+            }
+            if pa >= bottom {
+                if prev.is_null() {
+                    self.free = if next.is_null() {
+                        None
+                    } else {
+                        Some(Page::from(next))
+                    };
+                } else {
+                    Page::from(prev).write_next(next);
+                }
+                if !next.is_null() {
+                    Page::from(next).write_prev(prev);
+                }
+                page.zero_for_alloc();
+                unsafe {
+                    fence_rw();
+                }
+                self.used += 1;
+                if self.used > self.high_water_used {
+                    self.high_water_used = self.used;
+                }
+                return Some(page);
+            }
+            curr = if next.is_null() {
+                None
+            } else {
+                Some(Page::from(next))
+            };
+        }
+        None
+    }
+
+    // Walk the free list counting runs of contiguous pages.
+    // Returns (number of runs, length of the longest run in pages).
+    fn free_runs(&mut self) -> (usize, usize) {
+        let (mut runs, mut longest, mut run_len) = (0, 0, 0);
+        let mut last = core::ptr::null_mut::<usize>();
+        let mut curr = self.free;
+
+        while let Some(mut page) = curr {
+            if run_len != 0 && page.addr.addr() == last.addr() + PAGE_SIZE {
+                run_len += 1;
+            } else {
+                runs += 1;
+                run_len = 1;
+            }
+            longest = core::cmp::max(longest, run_len);
+            last = page.addr;
+
+            let (_, next) = page.read_free();
+            curr = if next.is_null() {
+                None
+            } else {
+                Some(Page::from(next))
+            };
+        }
+        (runs, longest)
+    }
+
     fn free_pages(&mut self, mut page: Page, num_pages: usize) {
         assert!(num_pages != 0, "Tried to free zero pages");
+        self.used -= num_pages;
         let example_null = core::ptr::null_mut::<usize>();
 
-        let mut region_end = Page::from(page.addr.map_addr(|addr| addr + (num_pages - 1) * 0x1000));
-        let stop = region_end.addr.map_addr(|addr| addr + 0x1000);
+        let mut region_end = Page::from(
+            page.addr
+                .map_addr(|addr| addr + (num_pages - 1) * PAGE_SIZE),
+        );
+        let stop = region_end.addr.map_addr(|addr| addr + PAGE_SIZE);
         let mut prev_page: Option<Page> = None;
         let mut curr_page = page;
         while curr_page.addr < stop {
             curr_page.zero();
-            let next_page = Page::from(curr_page.addr.map_addr(|addr| addr + 0x1000));
+            curr_page.set_zeroed(true);
+            let next_page = Page::from(curr_page.addr.map_addr(|addr| addr + PAGE_SIZE));
             match prev_page {
                 None => {
                     curr_page.write_next(next_page.addr);
@@ -323,10 +538,17 @@ impl Pool {
 }
 
 impl PagePool {
-    /// Create a new pool within a mutex spinlock.
-    pub fn new(bottom: *mut usize, top: *mut usize) -> Self {
-        assert!(is_multiple(bottom.addr(), PAGE_SIZE));
-        assert!(is_multiple(top.addr(), PAGE_SIZE));
+    /// Create a new pool within a mutex spinlock, covering the physical
+    /// pages in [bottom, top).
+    pub fn new(bottom: PhysAddr, top: PhysAddr) -> Self {
+        assert!(bottom.is_page_aligned());
+        assert!(top.is_page_aligned());
+        let (bottom, top): (*mut usize, *mut usize) = unsafe {
+            (
+                VirtAddr::from_phys(bottom).as_mut_ptr(),
+                VirtAddr::from_phys(top).as_mut_ptr(),
+            )
+        };
 
         // LEFT AS COMMENT FOR FUTURE REFERENCE:
         //let total_size = top.addr() - bottom.addr();
@@ -4,6 +4,7 @@
 // extern crate alloc;
 
 use crate::hw::HartContext;
+use crate::proc::kstack::KernelStack;
 use crate::trap::TrapFrame;
 use crate::vm::ptable::PageTable;
 use crate::vm::Resource;
@@ -17,6 +18,7 @@ pub struct Process {
     pgtbl: PageTable,
     trapframe: TrapFrame,
     ctx_regs: HartContext,
+    kstack: KernelStack, // Freed with the process.
 }
 
 pub enum ProcessState {
@@ -3,8 +3,10 @@
 // PTE size = 8 bytes
 use crate::hw::param::*;
 use crate::hw::riscv::*;
+use crate::vm::types::{PhysAddr, VirtAddr};
 use crate::vm::*;
 use core::assert;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 const VA_TOP: usize = 1 << (27 + 12); // 2^27 VPN + 12 Offset
 const PTE_TOP: usize = 512; // 4Kb / 8 byte PTEs = 512 PTEs / page!
@@ -16,9 +18,32 @@ const PTE_USER: usize = 1 << 4;
 const PTE_GLOBAL: usize = 1 << 5;
 const PTE_ACCESSED: usize = 1 << 6;
 const PTE_DIRTY: usize = 1 << 7;
+const PTE_COW: usize = 1 << 8; // First RSW bit: copy on write page.
+
+/// Refuse mappings that are both writable and executable. Only turn
+/// this off briefly, e.g. while copying code into place.
+pub static ENFORCE_WX: AtomicBool = AtomicBool::new(true);
+
+/// Allow writable and executable mappings until `enforce_wx`.
+pub fn relax_wx() {
+    log!(Warning, "W^X enforcement relaxed");
+    ENFORCE_WX.store(false, Ordering::Release);
+}
+
+/// Turn W^X enforcement back on.
+pub fn enforce_wx() {
+    ENFORCE_WX.store(true, Ordering::Release);
+}
+
+#[inline(always)]
+fn check_wx(flag: usize) -> Result<(), VmError> {
+    if flag & (PTE_WRITE | PTE_EXEC) == PTE_WRITE | PTE_EXEC && ENFORCE_WX.load(Ordering::Acquire) {
+        Err(VmError::WxViolation)
+    } else {
+        Ok(())
+    }
+}
 
-pub type VirtAddress = *mut usize;
-pub type PhysAddress = *mut usize;
 type PTEntry = usize;
 /// Supervisor Address Translation and Protection.
 /// Section 4.1.12 of risc-v priviliged ISA manual.
@@ -31,21 +56,21 @@ pub type SATPAddress = usize;
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct PageTable {
-    base: PhysAddress, // Page Table located at base address.
+    base: PhysAddr, // Page Table located at base address.
 }
 
 #[inline(always)]
-fn vpn(ptr: VirtAddress, level: usize) -> usize {
+fn vpn(ptr: VirtAddr, level: usize) -> usize {
     ptr.addr() >> (12 + 9 * level) & 0x1FF
 }
 
 #[inline(always)]
-fn pte_to_phy(pte: PTEntry) -> PhysAddress {
-    ((pte >> 10) << 12) as *mut usize
+fn pte_to_phy(pte: PTEntry) -> PhysAddr {
+    PhysAddr::new((pte >> 10) << 12)
 }
 
 #[inline(always)]
-fn phy_to_pte(ptr: PhysAddress) -> PTEntry {
+fn phy_to_pte(ptr: PhysAddr) -> PTEntry {
     ((ptr.addr()) >> 12) << 10
 }
 
@@ -61,20 +86,24 @@ macro_rules! PteSetFlag {
     };
 }
 
-#[inline(always)]
-fn phy_to_satp(ptr: PhysAddress) -> usize {
-    (1 << 63) | (ptr.addr() >> 12)
-}
+/// The translation mode our page tables are built for.
+pub const PAGING_MODE: SatpMode = SatpMode::Sv39;
 
-macro_rules! PageAlignDown {
-    ($p:expr) => {
-        ($p).map_addr(|addr| addr & !(PAGE_SIZE - 1))
-    };
+// walk_to and friends assume three levels of 512 entries. Sv48 needs a
+// fourth level first.
+const _: () = assert!(
+    matches!(PAGING_MODE, SatpMode::Sv39),
+    "Only Sv39 page tables are supported"
+);
+
+#[inline(always)]
+fn phy_to_satp(ptr: PhysAddr) -> usize {
+    PAGING_MODE.bits() | (ptr.addr() >> 12)
 }
 
 // Read the memory at location self + index * 8 bytes
-unsafe fn get_phy_offset(phy: PhysAddress, index: usize) -> *mut PTEntry {
-    phy.byte_add(index * 8)
+unsafe fn get_phy_offset(phy: PhysAddr, index: usize) -> *mut PTEntry {
+    VirtAddr::from_phys(phy.byte_add(index * 8)).as_mut_ptr()
 }
 
 fn set_pte(pte: *mut PTEntry, contents: PTEntry) {
@@ -105,25 +134,211 @@ impl PageTable {
         write_satp(phy_to_satp(self.base));
         flush_tlb();
     }
+
+    /// Translate `va` through this page table.
+    /// Returns None if `va` isn't mapped.
+    pub fn virt_to_phys(&self, va: VirtAddr) -> Option<PhysAddr> {
+        let (pte, level) = unsafe { walk_to(*self, va, 0, false) }.ok()?;
+        let pte = read_pte(pte);
+        if !PteGetFlag!(pte, PTE_VALID) {
+            return None;
+        }
+        Some(pte_to_phy(pte).byte_add(va.addr() & (level_size(level) - 1)))
+    }
+
+    /// The page table satp currently points at, None if paging is off.
+    pub fn current() -> Option<PageTable> {
+        let satp = read_satp();
+        if satp >> 60 == 0 {
+            return None;
+        }
+        Some(PageTable {
+            base: PhysAddr::new((satp & ((1 << 44) - 1)) << 12),
+        })
+    }
+
+    /// Is `va` mapped writable in this page table?
+    pub fn is_writable(&self, va: VirtAddr) -> bool {
+        match unsafe { walk_to(*self, va, 0, false) } {
+            Ok((pte, _)) => {
+                let pte = read_pte(pte);
+                PteGetFlag!(pte, PTE_VALID) && PteGetFlag!(pte, PTE_WRITE)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Copy this page table for a forked child. Every table page below
+    /// the root is copied rather than shared. User data pages stay
+    /// shared: their leaf PTEs lose write permission and gain PTE_COW, in
+    /// both parent and child, so the first write to one faults and can
+    /// copy it. Kernel mappings are shared as is.
+    pub fn clone_for_fork(&self) -> Result<PageTable, VmError> {
+        let child = clone_level(*self, 2)?;
+        // Only share the user pages once the copy can't fail any more.
+        share_user_leaves(*self, child, 2);
+        // The parent's writable user pages are now read only.
+        flush_tlb();
+        Ok(child)
+    }
+
+    /// Walk every level of this page table and check that valid non-leaf
+    /// PTEs point at page aligned tables in DRAM, and valid leaf PTEs
+    /// point into DRAM or device memory (everything below DRAM_BASE).
+    /// Returns the number of valid leaf mappings.
+    pub fn verify_consistency(&self) -> Result<usize, VmError> {
+        verify_level(*self, 2)
+    }
 }
 
-// Get the address of the PTE for va given the page table pt.
-// Returns Either PTE or None, callers responsibility to use PTE
-// or allocate a new page.
-unsafe fn walk(pt: PageTable, va: VirtAddress, alloc_new: bool) -> Result<*mut PTEntry, VmError> {
+// Copy one table at `level` and everything below it, leaf PTEs as they
+// are. On failure the partial copy is freed.
+fn clone_level(src: PageTable, level: usize) -> Result<PageTable, VmError> {
+    let dst = PageTable {
+        base: pt_palloc()?.phys_addr(),
+    };
+    for idx in 0..PTE_TOP {
+        let mut pte = read_pte(src.index_mut(idx));
+        if !PteGetFlag!(pte, PTE_VALID) {
+            continue;
+        }
+        if !pte_is_leaf(pte) {
+            // A level 0 PTE must be a leaf.
+            let next = match level {
+                0 => Err(VmError::BadPte),
+                _ => clone_level(PageTable::from(pte), level - 1),
+            };
+            match next {
+                Ok(table) => {
+                    let flags = pte & ((1 << 10) - 1);
+                    pte = phy_to_pte(table.base) | flags;
+                }
+                Err(e) => {
+                    free_tables(dst, level);
+                    return Err(e);
+                }
+            }
+        }
+        set_pte(dst.index_mut(idx), pte);
+    }
+    Ok(dst)
+}
+
+// For every user leaf of `src` at `level` and below, which `dst` is an
+// exact copy of: count the child's mapping of each frame the leaf covers,
+// and make writable leaves COW in both tables.
+fn share_user_leaves(src: PageTable, dst: PageTable, level: usize) {
+    for idx in 0..PTE_TOP {
+        let pte = read_pte(src.index_mut(idx));
+        if !PteGetFlag!(pte, PTE_VALID) {
+            continue;
+        }
+        if !pte_is_leaf(pte) {
+            let child = read_pte(dst.index_mut(idx));
+            share_user_leaves(PageTable::from(pte), PageTable::from(child), level - 1);
+            continue;
+        }
+        if !PteGetFlag!(pte, PTE_USER) {
+            continue;
+        }
+        if let Some(refs) = super::refcount::page_refs() {
+            let base = pte_to_phy(pte);
+            for offset in (0..level_size(level)).step_by(PAGE_SIZE) {
+                let page = unsafe { VirtAddr::from_phys(base.byte_add(offset)) }.as_mut_ptr();
+                refs.inc(&palloc::Page::from(page));
+            }
+        }
+        if PteGetFlag!(pte, PTE_WRITE) {
+            let pte = (pte & !PTE_WRITE) | PTE_COW;
+            set_pte(src.index_mut(idx), pte);
+            set_pte(dst.index_mut(idx), pte);
+        }
+    }
+}
+
+// Free the table pages (not the leaf pages they map) of a table at `level`.
+fn free_tables(table: PageTable, level: usize) {
+    for idx in 0..PTE_TOP {
+        let pte = read_pte(table.index_mut(idx));
+        if PteGetFlag!(pte, PTE_VALID) && !pte_is_leaf(pte) && level > 0 {
+            free_tables(PageTable::from(pte), level - 1);
+        }
+    }
+    let page = unsafe { VirtAddr::from_phys(table.base) }.as_mut_ptr();
+    let _ = pfree(palloc::Page::from(page));
+}
+
+fn in_dram(pa: PhysAddr) -> bool {
+    DRAM_BASE.addr() <= pa.addr() && pa.addr() < dram_end().addr()
+}
+
+fn verify_level(table: PageTable, level: usize) -> Result<usize, VmError> {
+    let mut leaves = 0;
+    for idx in 0..PTE_TOP {
+        let pte = read_pte(table.index_mut(idx));
+        if !PteGetFlag!(pte, PTE_VALID) {
+            continue;
+        }
+        let pa = pte_to_phy(pte);
+        if pte_is_leaf(pte) {
+            // Leaf, possibly a superpage.
+            if pa.addr() & (level_size(level) - 1) != 0 {
+                log!(
+                    Error,
+                    "Misaligned superpage pte {:#x} at level {}",
+                    pte,
+                    level
+                );
+                return Err(VmError::BadPte);
+            }
+            if !in_dram(pa) && pa.addr() >= DRAM_BASE.addr() {
+                log!(
+                    Error,
+                    "Leaf pte {:#x} at level {} maps past DRAM",
+                    pte,
+                    level
+                );
+                return Err(VmError::BadPte);
+            }
+            leaves += 1;
+        } else {
+            // Pointer to the next level table.
+            if level == 0 || !pa.is_page_aligned() || !in_dram(pa) {
+                log!(Error, "Bad non-leaf pte {:#x} at level {}", pte, level);
+                return Err(VmError::BadPte);
+            }
+            leaves += verify_level(PageTable::from(pte), level - 1)?;
+        }
+    }
+    Ok(leaves)
+}
+
+// Get the address of the PTE for va at `target` level of the page table
+// pt, where level 0 holds 4K leaves, level 1 2M megapages and level 2
+// 1G gigapages.
+// Stops early and returns a superpage leaf if it finds one above
+// `target`. Returns the PTE and the level it was found at, callers
+// responsibility to check flags.
+unsafe fn walk_to(
+    pt: PageTable,
+    va: VirtAddr,
+    target: usize,
+    alloc_new: bool,
+) -> Result<(*mut PTEntry, usize), VmError> {
     let mut table = pt;
     assert!(va.addr() < VA_TOP);
-    for level in (1..3).rev() {
+    for level in (target + 1..3).rev() {
         let idx = vpn(va, level);
         let next: *mut PTEntry = table.index_mut(idx);
         table = match PteGetFlag!(*next, PTE_VALID) {
+            true if pte_is_leaf(*next) => return Ok((next, level)),
             true => PageTable::from(*next),
             false => {
                 if alloc_new {
-                    match PAGEPOOL.get_mut().unwrap().palloc() {
+                    match pt_palloc() {
                         Ok(pg) => {
-                            *next = PteSetFlag!(phy_to_pte(pg.addr), PTE_VALID);
-                            PageTable::from(phy_to_pte(pg.addr))
+                            *next = PteSetFlag!(phy_to_pte(pg.phys_addr()), PTE_VALID);
+                            PageTable::from(phy_to_pte(pg.phys_addr()))
                         }
                         Err(e) => return Err(e),
                     }
@@ -133,46 +348,234 @@ unsafe fn walk(pt: PageTable, va: VirtAddress, alloc_new: bool) -> Result<*mut P
             }
         };
     }
-    // Last, return PTE leaf. Assuming we are all using 4K pages right now.
-    // Caller's responsibility to check flags.
-    let idx = vpn(va, 0);
-    Ok(table.index_mut(idx))
+    let idx = vpn(va, target);
+    Ok((table.index_mut(idx), target))
+}
+
+// Get the address of the leaf PTE for va given the page table pt.
+// Returns Either PTE or None, callers responsibility to use PTE
+// or allocate a new page.
+unsafe fn walk(pt: PageTable, va: VirtAddr, alloc_new: bool) -> Result<*mut PTEntry, VmError> {
+    walk_to(pt, va, 0, alloc_new).map(|(pte, _)| pte)
+}
+
+#[inline(always)]
+fn pte_is_leaf(pte: PTEntry) -> bool {
+    PteGetFlag!(pte, PTE_READ | PTE_WRITE | PTE_EXEC)
+}
+
+/// Bytes mapped by a leaf PTE at `level`.
+#[inline(always)]
+fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+/// Pick the level to map `start` at: the biggest superpage (1G, then 2M)
+/// that both addresses are aligned to, that fits before `end`, and whose
+/// PTE is still free. Otherwise a 4K page.
+unsafe fn map_level(
+    pt: PageTable,
+    start: VirtAddr,
+    phys: PhysAddr,
+    end: VirtAddr,
+) -> Result<usize, VmError> {
+    for level in (1..=2).rev() {
+        let huge = level_size(level);
+        if (start.addr() | phys.addr()) & (huge - 1) != 0 || end.addr() - start.addr() < huge {
+            continue;
+        }
+        let (pte, found) = walk_to(pt, start, level, true)?;
+        if found == level && read_pte(pte) & PTE_VALID == 0 {
+            return Ok(level);
+        }
+    }
+    Ok(0)
 }
 
 /// Maps some number of pages into the VM given by pt of byte length
-/// size.
+/// size. Uses gigapages and megapages for aligned parts of the range.
 fn page_map(
     pt: PageTable,
-    va: VirtAddress,
-    pa: PhysAddress,
+    va: VirtAddr,
+    pa: PhysAddr,
     size: usize,
     flag: usize,
 ) -> Result<(), VmError> {
+    check_wx(flag)?;
     // Round down to page aligned boundary (multiple of pg size).
-    let mut start = PageAlignDown!(va);
+    let mut start = va.page_align_down();
     let mut phys = pa;
-    let end = PageAlignDown!(va.map_addr(|addr| addr + (size - 1)));
+    let end = va.byte_add(size - 1).page_align_down().byte_add(PAGE_SIZE);
 
-    while start <= end {
-        let walk_addr = unsafe { walk(pt, start, true) };
-        match walk_addr {
-            Err(e) => {
-                return Err(e);
-            }
-            Ok(pte_addr) => {
-                if read_pte(pte_addr) & PTE_VALID != 0 {
-                    return Err(VmError::PallocFail);
-                }
-                set_pte(pte_addr, PteSetFlag!(phy_to_pte(phys), flag | PTE_VALID));
-                start = start.map_addr(|addr| addr + PAGE_SIZE);
-                phys = phys.map_addr(|addr| addr + PAGE_SIZE);
-            }
+    while start < end {
+        let level = unsafe { map_level(pt, start, phys, end) }?;
+        let (pte_addr, found) = unsafe { walk_to(pt, start, level, true) }?;
+        if found != level || read_pte(pte_addr) & PTE_VALID != 0 {
+            return Err(VmError::PallocFail);
+        }
+        set_pte(pte_addr, PteSetFlag!(phy_to_pte(phys), flag | PTE_VALID));
+        start = start.byte_add(level_size(level));
+        phys = phys.byte_add(level_size(level));
+    }
+    // Only new mappings, but invalid PTEs may be cached too.
+    unsafe {
+        sfence_vma();
+    }
+
+    Ok(())
+}
+
+/// Map `megabytes` of physical memory at `pa` to `va` with 2M megapages.
+/// Both addresses must be 2M aligned and megabytes a multiple of 2.
+pub fn map_huge(
+    pt: PageTable,
+    va: VirtAddr,
+    pa: PhysAddr,
+    megabytes: usize,
+    flag: usize,
+) -> Result<(), VmError> {
+    let huge = level_size(1);
+    assert!(va.addr() & (huge - 1) == 0 && pa.addr() & (huge - 1) == 0 && megabytes & 1 == 0);
+    page_map(pt, va, pa, megabytes << 20, flag)
+}
+
+/// Map the 1G of physical memory at `pa` to `va` with a single root
+/// level PTE. Both addresses must be 1G aligned.
+pub fn map_gigapage(pt: PageTable, va: VirtAddr, pa: PhysAddr, flag: usize) -> Result<(), VmError> {
+    let giga = level_size(2);
+    assert!((va.addr() | pa.addr()) & (giga - 1) == 0);
+    page_map(pt, va, pa, giga, flag)
+}
+
+/// Like `page_map`, but replaces any existing leaf mappings in the range
+/// instead of failing on them. A megapage that is only partly covered by
+/// the range can't be replaced, that fails with BadPte.
+fn page_remap(
+    pt: PageTable,
+    va: VirtAddr,
+    pa: PhysAddr,
+    size: usize,
+    flag: usize,
+) -> Result<(), VmError> {
+    check_wx(flag)?;
+    let mut start = va.page_align_down();
+    let mut phys = pa;
+    let end = va.byte_add(size - 1).page_align_down().byte_add(PAGE_SIZE);
+
+    while start < end {
+        let level = unsafe { map_level(pt, start, phys, end) }?;
+        let (pte_addr, level) = unsafe { walk_to(pt, start, level, true) }?;
+        let size = level_size(level);
+        if (start.addr() | phys.addr()) & (size - 1) != 0 || end.addr() - start.addr() < size {
+            return Err(VmError::BadPte);
         }
+        set_pte(pte_addr, PteSetFlag!(phy_to_pte(phys), flag | PTE_VALID));
+        start = start.byte_add(level_size(level));
+        phys = phys.byte_add(level_size(level));
     }
+    flush_tlb();
 
     Ok(())
 }
 
+/// Make the kernel's .rodata section permanently read only, replacing
+/// whatever mapping it had in pt.
+pub fn protect_rodata(pt: PageTable) -> Result<(), VmError> {
+    let (start, end) = (text_end().addr(), rodata_end().addr());
+    page_remap(
+        pt,
+        VirtAddr::new(start),
+        PhysAddr::new(start),
+        end - start,
+        PTE_READ,
+    )
+}
+
+/// Clear the leaf PTEs for the `size` bytes starting at `va` in pt.
+/// Pages in the range that were never mapped are skipped. Superpages
+/// must lie wholly inside the range, if one is only partly covered
+/// nothing is unmapped and this fails with BadPte. Intermediate page
+/// table pages are left in place.
+fn page_unmap(pt: PageTable, va: VirtAddr, size: usize) -> Result<(), VmError> {
+    let start = va.page_align_down();
+    let end = va.byte_add(size - 1).page_align_down().byte_add(PAGE_SIZE);
+
+    // Check the whole range first, then clear it.
+    for clear in [false, true] {
+        let mut curr = start;
+        while curr < end {
+            let step = match unsafe { walk_to(pt, curr, 0, false) } {
+                Ok((pte_addr, level)) => {
+                    let size = level_size(level);
+                    let base = curr.addr() & !(size - 1);
+                    if base != curr.addr() || end.addr() - curr.addr() < size {
+                        return Err(VmError::BadPte);
+                    }
+                    if clear {
+                        set_pte(pte_addr, 0);
+                    }
+                    size
+                }
+                Err(_) => PAGE_SIZE,
+            };
+            curr = curr.byte_add(step);
+        }
+    }
+    flush_tlb();
+    Ok(())
+}
+
+// Upper bound on the number of ranges kpage_init maps: UART, test device,
+// CLINT msip and mtime, text, rodata, data, bss and heap, plus a stack and two interrupt stacks per hart.
+const KMAP_MAX: usize = 9 + 3 * NHART;
+
+/// Stack of the ranges `kpage_init` has mapped so far, so that a
+/// failure part way through can be unwound in reverse order.
+struct MapStack {
+    pt: PageTable,
+    ranges: [(VirtAddr, usize); KMAP_MAX],
+    len: usize,
+}
+
+impl MapStack {
+    fn new(pt: PageTable) -> Self {
+        MapStack {
+            pt,
+            ranges: [(VirtAddr::new(0), 0); KMAP_MAX],
+            len: 0,
+        }
+    }
+
+    /// Map a range into the page table and remember it. If the mapping
+    /// fails, unmap whatever part of it was installed along with every
+    /// range mapped before it, leaving the page table as we found it.
+    fn map(&mut self, va: VirtAddr, pa: PhysAddr, size: usize, flag: usize) -> Result<(), VmError> {
+        assert!(self.len < KMAP_MAX, "kpage_init mapped too many ranges");
+        if let Err(e) = page_map(self.pt, va, pa, size, flag) {
+            // page_map only uses superpages the range covers whole.
+            page_unmap(self.pt, va, size).expect("Unmapping a partial kernel mapping failed");
+            self.unwind();
+            return Err(e);
+        }
+        self.ranges[self.len] = (va, size);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Map `size` bytes at physical address `start` 1:1 into the table.
+    fn map_ident(&mut self, start: usize, size: usize, flag: usize) -> Result<(), VmError> {
+        self.map(VirtAddr::new(start), PhysAddr::new(start), size, flag)
+    }
+
+    fn unwind(&mut self) {
+        while self.len > 0 {
+            self.len -= 1;
+            let (va, size) = self.ranges[self.len];
+            page_unmap(self.pt, va, size).expect("Unmapping a kernel mapping failed");
+        }
+    }
+}
+
 /// Create the kernel page table with 1:1 mappings to physical memory.
 /// First allocate a new page for the kernel page table.
 /// Next, map memory mapped I/O devices to the kernel page table.
@@ -180,32 +583,53 @@ fn page_map(
 /// Additionally, map a stack+guard page for each hart.
 /// Finally map, the remaining physical memory to kernel virtual memory as
 /// the kernel 'heap'.
+/// If any mapping fails, everything mapped so far is unmapped again and
+/// every page table page, the root included, goes back to the page pool
+/// before the error is returned.
 pub fn kpage_init() -> Result<PageTable, VmError> {
-    let base = unsafe {
-        PAGEPOOL
-            .get_mut()
-            .unwrap()
-            .palloc()
-            .expect("Couldn't allocate root kernel page table.")
-    };
+    let base = pt_palloc()?;
     //log!(Debug, "Kernel page table base addr: {:#02x}", base.addr.addr());
     let kpage_table = PageTable {
-        base: base.addr as *mut usize,
+        base: base.phys_addr(),
     };
+    match map_kernel(kpage_table) {
+        Ok(()) => Ok(kpage_table),
+        Err(e) => {
+            free_tables(kpage_table, 2);
+            Err(e)
+        }
+    }
+}
 
-    page_map(
-        kpage_table,
-        UART_BASE as *mut usize,
-        UART_BASE as *mut usize,
-        PAGE_SIZE,
-        PTE_READ | PTE_WRITE,
-    )?;
+// The mappings of kpage_init, into the empty table kpage_table.
+fn map_kernel(kpage_table: PageTable) -> Result<(), VmError> {
+    let mut mapped = MapStack::new(kpage_table);
+
+    mapped.map_ident(UART_BASE, PAGE_SIZE, PTE_READ | PTE_WRITE)?;
     log!(Debug, "Successfully mapped UART into kernel pgtable...");
 
-    page_map(
-        kpage_table,
-        DRAM_BASE,
-        DRAM_BASE as *mut usize,
+    // Just the msip page, so harts can send each other interrupts.
+    mapped.map_ident(CLINT_BASE, PAGE_SIZE, PTE_READ | PTE_WRITE)?;
+    log!(
+        Debug,
+        "Successfully mapped CLINT msip into kernel pgtable..."
+    );
+
+    // And the mtime page, read only, to read the time from supervisor mode.
+    mapped.map_ident(CLINT_BASE + 0xB000, PAGE_SIZE, PTE_READ)?;
+    log!(
+        Debug,
+        "Successfully mapped CLINT mtime into kernel pgtable..."
+    );
+
+    mapped.map_ident(VIRT_TEST_BASE, PAGE_SIZE, PTE_READ | PTE_WRITE)?;
+    log!(
+        Debug,
+        "Successfully mapped test device into kernel pgtable..."
+    );
+
+    mapped.map_ident(
+        DRAM_BASE.addr(),
         text_end().addr() - DRAM_BASE.addr(),
         PTE_READ | PTE_EXEC,
     )?;
@@ -214,10 +638,8 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
         "Succesfully mapped kernel text into kernel pgtable..."
     );
 
-    page_map(
-        kpage_table,
-        text_end(),
-        text_end() as *mut usize,
+    mapped.map_ident(
+        text_end().addr(),
         rodata_end().addr() - text_end().addr(),
         PTE_READ,
     )?;
@@ -226,10 +648,8 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
         "Succesfully mapped kernel rodata into kernel pgtable..."
     );
 
-    page_map(
-        kpage_table,
-        rodata_end(),
-        rodata_end() as *mut usize,
+    mapped.map_ident(
+        rodata_end().addr(),
         data_end().addr() - rodata_end().addr(),
         PTE_READ | PTE_WRITE,
     )?;
@@ -243,13 +663,7 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
     let base = stacks_start();
     for s in 0..NHART {
         let stack = unsafe { base.byte_add(PAGE_SIZE * (1 + s * 3)) };
-        page_map(
-            kpage_table,
-            stack,
-            stack,
-            PAGE_SIZE * 2,
-            PTE_READ | PTE_WRITE,
-        )?;
+        mapped.map_ident(stack.addr(), PAGE_SIZE * 2, PTE_READ | PTE_WRITE)?;
         log!(
             Debug,
             "Succesfully mapped kernel stack {} into kernel pgtable...",
@@ -263,22 +677,10 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
     for i in 0..NHART {
         let m_intstack = unsafe { base.byte_add(PAGE_SIZE * (1 + i * 4)) };
         // Map hart i m-mode handler.
-        page_map(
-            kpage_table,
-            m_intstack,
-            m_intstack,
-            PAGE_SIZE,
-            PTE_READ | PTE_WRITE,
-        )?;
+        mapped.map_ident(m_intstack.addr(), PAGE_SIZE, PTE_READ | PTE_WRITE)?;
         // Map hart i s-mode handler
         let s_intstack = unsafe { m_intstack.byte_add(PAGE_SIZE * 2) };
-        page_map(
-            kpage_table,
-            s_intstack,
-            s_intstack,
-            PAGE_SIZE,
-            PTE_READ | PTE_WRITE,
-        )?;
+        mapped.map_ident(s_intstack.addr(), PAGE_SIZE, PTE_READ | PTE_WRITE)?;
         log!(
             Debug,
             "Succesfully mapped interrupt stack for hart {} into kernel pgtable...",
@@ -286,23 +688,60 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
         );
     }
 
-    page_map(
-        kpage_table,
-        bss_start(),
-        bss_start(),
+    mapped.map_ident(
+        bss_start().addr(),
         bss_end().addr() - bss_start().addr(),
         PTE_READ | PTE_WRITE,
     )?;
     log!(Debug, "Succesfully mapped kernel bss...");
 
-    page_map(
-        kpage_table,
-        bss_end(),
-        bss_end(),
+    mapped.map_ident(
+        bss_end().addr(),
         dram_end().addr() - bss_end().addr(),
         PTE_READ | PTE_WRITE,
     )?;
     log!(Debug, "Succesfully mapped kernel heap...");
 
-    Ok(kpage_table)
+    protect_rodata(kpage_table)?;
+    log!(Debug, "Succesfully protected kernel rodata...");
+
+    let leaves = kpage_table.verify_consistency()?;
+    log!(
+        Debug,
+        "Kernel pgtable is consistent, {} leaf mappings...",
+        leaves
+    );
+
+    Ok(())
+}
+
+/// Write a nop sled ending in `ret` to a fresh page, map it executable,
+/// flush the instruction cache and call it. Run at boot, after vm::init.
+pub fn test_icache_flush() {
+    // Unused, well away from the identity mapped DRAM and devices.
+    const SLED_VA: usize = 0x20_0000_0000;
+    const NOP: u32 = 0x0000_0013; // addi x0, x0, 0
+    const RET: u32 = 0x0000_8067; // jalr x0, 0(ra)
+
+    let pt = PageTable::current().expect("Paging is off");
+    let page = palloc().expect("No page for the nop sled");
+    let code = page.addr.cast::<u32>();
+    unsafe {
+        for i in 0..15 {
+            code.add(i).write(NOP);
+        }
+        code.add(15).write(RET);
+    }
+    let va = VirtAddr::new(SLED_VA);
+    page_map(pt, va, page.phys_addr(), PAGE_SIZE, PTE_READ | PTE_EXEC)
+        .expect("Mapping the nop sled failed");
+    flush_icache_range(va.addr(), 16 * 4).expect("Icache flush IPI not delivered");
+
+    let sled: extern "C" fn() = unsafe { core::mem::transmute(va.addr()) };
+    sled();
+
+    // The table pages for SLED_VA stay, for the next caller.
+    page_unmap(pt, va, PAGE_SIZE).expect("Unmapping the nop sled failed");
+    let _ = pfree(page);
+    log!(Debug, "Successful test of instruction cache flush...");
 }
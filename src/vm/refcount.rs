@@ -0,0 +1,99 @@
+//! Mappings per physical page frame, for copy on write.
+use core::cell::OnceCell;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::palloc::Page;
+use super::types::PhysAddr;
+use crate::hw::param::{page_align_up, DRAM_BASE, PAGE_SHIFT, PAGE_SIZE};
+
+/// Count of a page that must never be freed by COW, e.g. the kernel image.
+pub const PINNED: u16 = u16::MAX;
+
+/// One counter per page frame of DRAM.
+pub struct PageRefCount {
+    counts: &'static mut [AtomicU16],
+}
+
+static mut PAGE_REFS: OnceCell<PageRefCount> = OnceCell::new();
+
+impl PageRefCount {
+    /// Bytes of counters needed for DRAM up to `memory_end`, rounded up
+    /// to whole pages.
+    pub fn table_size(memory_end: PhysAddr) -> usize {
+        let frames = (memory_end.addr() - DRAM_BASE.addr()) >> PAGE_SHIFT;
+        page_align_up(frames * core::mem::size_of::<AtomicU16>())
+    }
+
+    /// Set up the counters in `table`, which must be `table_size` bytes.
+    /// Frames below `first_free` (the kernel image) and the table's own
+    /// pages are pinned, the rest start at zero.
+    /// # Safety
+    /// `table` must be memory nothing else will ever use.
+    pub unsafe fn new(table: *mut usize, memory_end: PhysAddr, first_free: PhysAddr) -> Self {
+        let frames = (memory_end.addr() - DRAM_BASE.addr()) >> PAGE_SHIFT;
+        let counts = core::slice::from_raw_parts_mut(table.cast::<AtomicU16>(), frames);
+        let table_end = table.addr() + Self::table_size(memory_end);
+        for (frame, count) in counts.iter_mut().enumerate() {
+            let pa = DRAM_BASE.addr() + (frame << PAGE_SHIFT);
+            let pinned = pa < first_free.addr() || (table.addr()..table_end).contains(&pa);
+            *count = AtomicU16::new(if pinned { PINNED } else { 0 });
+        }
+        PageRefCount { counts }
+    }
+
+    fn count(&self, page: &Page) -> &AtomicU16 {
+        let pa = page.phys_addr().addr();
+        assert!(pa & (PAGE_SIZE - 1) == 0 && pa >= DRAM_BASE.addr());
+        &self.counts[(pa - DRAM_BASE.addr()) >> PAGE_SHIFT]
+    }
+
+    /// A freshly allocated user page has one mapping.
+    pub fn init_user(&self, page: &Page) {
+        self.count(page).store(1, Ordering::Relaxed);
+    }
+
+    /// Add a mapping of `page`. Pinned pages stay pinned.
+    pub fn inc(&self, page: &Page) {
+        let _ = self
+            .count(page)
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| match c {
+                PINNED => None,
+                c if c == PINNED - 1 => panic!("Page mapped too many times"),
+                c => Some(c + 1),
+            });
+    }
+
+    /// Drop a mapping of `page`, returning how many are left. The page can
+    /// be freed once that's zero.
+    pub fn dec(&self, page: &Page) -> u16 {
+        // AcqRel so whoever frees the page sees every other unmapper's writes.
+        match self
+            .count(page)
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| match c {
+                PINNED => None,
+                0 => panic!("Page refcount underflow"),
+                c => Some(c - 1),
+            }) {
+            Ok(old) => old - 1,
+            Err(_) => PINNED,
+        }
+    }
+
+    pub fn get(&self, page: &Page) -> u16 {
+        self.count(page).load(Ordering::Relaxed)
+    }
+}
+
+/// Install the global counters. Called once from vm::init.
+pub fn init(refs: PageRefCount) {
+    unsafe {
+        if (*core::ptr::addr_of_mut!(PAGE_REFS)).set(refs).is_err() {
+            panic!("Page refcounts double init.")
+        }
+    }
+}
+
+/// The global counters, once vm::init has set them up.
+pub fn page_refs() -> Option<&'static PageRefCount> {
+    unsafe { (*core::ptr::addr_of!(PAGE_REFS)).get() }
+}
@@ -0,0 +1,76 @@
+//! Physical and virtual address types.
+// A raw `*mut usize` says nothing about which address space it lives
+// in. These don't convert into each other, except through an explicit
+// (unsafe) conversion or a page table walk.
+use crate::hw::param::{page_align_down, PAGE_MASK};
+
+/// Offset of the kernel's view of physical memory. The kernel page table
+/// maps physical memory 1:1, so this is zero for now.
+pub const KERNEL_MMU_OFFSET: usize = 0;
+
+/// A physical memory address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct PhysAddr(usize);
+
+/// A virtual memory address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct VirtAddr(usize);
+
+impl PhysAddr {
+    pub const fn new(addr: usize) -> Self {
+        PhysAddr(addr)
+    }
+
+    pub const fn addr(self) -> usize {
+        self.0
+    }
+
+    pub const fn byte_add(self, bytes: usize) -> Self {
+        PhysAddr(self.0 + bytes)
+    }
+
+    pub const fn page_align_down(self) -> Self {
+        PhysAddr(page_align_down(self.0))
+    }
+
+    pub const fn is_page_aligned(self) -> bool {
+        self.0 & PAGE_MASK == 0
+    }
+}
+
+impl VirtAddr {
+    pub const fn new(addr: usize) -> Self {
+        VirtAddr(addr)
+    }
+
+    pub const fn addr(self) -> usize {
+        self.0
+    }
+
+    pub const fn byte_add(self, bytes: usize) -> Self {
+        VirtAddr(self.0 + bytes)
+    }
+
+    pub const fn page_align_down(self) -> Self {
+        VirtAddr(page_align_down(self.0))
+    }
+
+    pub const fn is_page_aligned(self) -> bool {
+        self.0 & PAGE_MASK == 0
+    }
+
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    /// The kernel virtual address through which physical address `p`
+    /// can be accessed.
+    /// # Safety
+    /// Only meaningful once the kernel page table maps `p`, i.e. for
+    /// addresses in DRAM or a mapped MMIO device.
+    pub unsafe fn from_phys(p: PhysAddr) -> VirtAddr {
+        VirtAddr(p.0 + KERNEL_MMU_OFFSET)
+    }
+}
@@ -1,13 +1,26 @@
 //! Kernel Virtual Memory Allocator.
+use core::marker::PhantomData;
 use core::mem::size_of;
 
-use super::{palloc, palloc::Page, pfree, VmError};
-use crate::hw::param::PAGE_SIZE;
+use super::journal::{record, AllocOp};
+use super::types::PhysAddr;
+use super::{
+    palloc, palloc::Page, palloc_range, pfree, relieve_pressure, KernelAllocator, VmError,
+};
+use crate::hw::param::{page_align_down, PAGE_MASK, PAGE_SIZE};
+use crate::hw::riscv::InterruptGuard;
 
 pub const MAX_CHUNK_SIZE: usize = 4080; // PAGE_SIZE - ZONE_HEADER_SIZE - HEADER_SIZE = 4096 - 8 - 8 = 4080.
 const HEADER_SIZE: usize = size_of::<Header>();
 const ZONE_SIZE: usize = 8;
 const HEADER_USED: usize = 1 << 12; // Chunk is in use flag.
+                                    // With the debug_kalloc feature, free chunk data is filled with this.
+const POISON: u8 = 0xDE;
+const POISON_WORD: usize = usize::from_ne_bytes([POISON; 8]);
+// With debug_kalloc, the last word of every used chunk holds this, so
+// overflows are caught on free.
+const REDZONE: usize = 0xCAFEBABECAFEBABE;
+const REDZONE_SIZE: usize = if cfg!(feature = "debug_kalloc") { 8 } else { 0 };
 
 // 8 byte minimum allocation size,
 // 4096-8-8=4080 byte maximum allocation size.
@@ -48,7 +61,9 @@ struct Zone {
 /// Kalloc is comprised of `Zones` (physical pages). Each
 /// zone is broken up into smaller chunks as memory is allocated
 /// and merged into larger chunks as memory is deallocated.
-/// Each allocation, `x` , must satisfy `0<= x <= 4080` bytes.
+/// Each allocation, `x` , must satisfy `0<= x <= 4080` bytes to fit
+/// in a zone. Allocations of `4080 < x <= PAGE_SIZE` bytes get a whole
+/// page of their own straight from palloc.
 /// All allocations will be automatically rounded up to be
 /// 8 byte aligned.
 ///
@@ -111,17 +126,75 @@ struct Zone {
 pub struct Kalloc {
     head: *mut usize, // Address of first zone.
     end: *mut usize,
+    used_bytes: usize,            // Bytes currently handed out.
+    high_water_used_bytes: usize, // Peak of used_bytes.
+    fit_policy: FitPolicy,
+}
+
+/// How a zone picks the free chunk to allocate from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FitPolicy {
+    /// The first chunk that is big enough. Fast, but splits big chunks
+    /// for small requests.
+    FirstFit,
+    /// The smallest chunk that is big enough, at the cost of scanning
+    /// the whole zone.
+    BestFit,
+}
+
+// The zones are only reached through the Kalloc that owns them.
+unsafe impl Send for Kalloc {}
+
+/// Snapshot of a `Kalloc` pool's usage.
+#[derive(Copy, Clone, Debug)]
+pub struct KallocStats {
+    pub used_bytes: usize,
+    pub high_water_used_bytes: usize,
+    /// Free chunk bytes across all zones.
+    pub free_bytes: usize,
+    /// Largest single free chunk.
+    pub largest_free_chunk: usize,
+}
+
+impl KallocStats {
+    /// Percentage of free zone memory that isn't in the largest free
+    /// chunk. 0 means all of it could serve a single allocation.
+    pub fn fragmentation_ratio(&self) -> usize {
+        (self.largest_free_chunk * 100)
+            .checked_div(self.free_bytes)
+            .map_or(0, |largest| 100 - largest)
+    }
 }
 
 #[derive(Debug)]
 pub enum KallocError {
+    AlignmentError(usize),
+    HeapOverflow(*mut usize),
     MaxRefs,
     MinRefs,
     NullZone,
     OOM,
+    SizeTooLarge(usize),
     Void,
 }
 
+/// Failing to get a page for a new zone leaves Kalloc out of memory,
+/// whatever the page allocator's reason was.
+impl From<VmError> for KallocError {
+    fn from(_: VmError) -> Self {
+        KallocError::OOM
+    }
+}
+
+impl From<KallocError> for VmError {
+    fn from(e: KallocError) -> Self {
+        match e {
+            KallocError::OOM => VmError::OutOfPages,
+            e => VmError::Kalloc(e),
+        }
+    }
+}
+
 impl From<*mut usize> for Header {
     fn from(src: *mut usize) -> Self {
         let fields = unsafe { src.read() };
@@ -183,12 +256,41 @@ impl Header {
         let size = self.chunk_size() + HEADER_SIZE + next.chunk_size();
         self.set_size(size);
         //self.write_to(addr);
+        // The old header is now free chunk data.
+        let dead = if cfg!(feature = "debug_kalloc") {
+            POISON_WORD
+        } else {
+            0
+        };
+        unsafe {
+            next_addr.write(dead);
+        }
+    }
+}
+
+/// debug_kalloc: fill `size` bytes of free chunk data at `data` with POISON.
+fn poison(data: *mut usize, size: usize) {
+    if cfg!(feature = "debug_kalloc") {
         unsafe {
-            next_addr.write(0);
+            data.cast::<u8>().write_bytes(POISON, size);
         }
     }
 }
 
+/// debug_kalloc: a free chunk's first data word must still be poisoned,
+/// otherwise something wrote to it after it was freed.
+fn check_poison(data: *mut usize) {
+    if cfg!(feature = "debug_kalloc") {
+        let word = unsafe { data.read() };
+        assert!(
+            word == POISON_WORD,
+            "Kalloc use after free at {:?}: {:#x}",
+            data,
+            word
+        );
+    }
+}
+
 // Assumes the first usize of a zone is the zone header.
 // Next usize is the chunk header.
 impl From<*mut usize> for Zone {
@@ -206,11 +308,11 @@ impl Zone {
     }
 
     fn get_refs(&self) -> usize {
-        self.next & (4095)
+        self.next & PAGE_MASK
     }
 
     fn get_next(&self) -> Result<usize, KallocError> {
-        let next_addr = self.next & !(PAGE_SIZE - 1);
+        let next_addr = page_align_down(self.next);
         if next_addr == 0x0 {
             Err(KallocError::NullZone)
         } else {
@@ -290,38 +392,250 @@ impl Zone {
         let _ = pfree(Page::from(self.base));
     }
 
-    // Scan this zone for the first free chunk of size >= requested size.
-    // First 8 bytes of a zone is the Zone.next field.
-    // Second 8 bytes is the first header of the zone.
-    fn scan(&mut self, size: usize) -> Option<*mut usize> {
-        // Start and end (start + PAGE_SIZE) bounds of zone.
-        let (mut curr, end) = unsafe { (self.base.add(1), self.base.add(PAGE_SIZE / 8)) };
-        // Get the first header in the zone.
-        let mut head = Header::from(curr);
-
-        while curr < end {
-            let chunk_size = head.chunk_size();
-            if chunk_size < size || !head.is_free() {
-                let (mut prev, trail) = (head, curr);
-                curr = curr.map_addr(|addr| addr + HEADER_SIZE + chunk_size);
-                head = Header::from(curr);
-
-                // TODO: Is not pretty, make pretty.
-                if prev.is_free() && head.is_free() {
-                    prev.merge(head, curr);
-                    prev.write_to(trail);
-                    (head, curr) = (prev, trail);
+    /// Iterate over the chunks in this zone, from the first chunk header
+    /// up to the end of the zone's page.
+    fn chunks(&self) -> ChunkIter<'_> {
+        // First 8 bytes of a zone is the Zone.next field.
+        // Second 8 bytes is the first header of the zone.
+        let (curr, end) = unsafe { (self.base.add(1), self.base.add(PAGE_SIZE / 8)) };
+        ChunkIter {
+            curr,
+            end,
+            _zone: PhantomData,
+        }
+    }
+
+    /// Iterate over the chunks in this zone starting at the chunk header
+    /// at `start`.
+    fn chunks_from(&self, start: *mut usize) -> ChunkIter<'_> {
+        ChunkIter {
+            curr: start,
+            ..self.chunks()
+        }
+    }
+
+    // Scan this zone for a run of free chunks with room for the requested
+    // size: the first one, or with BestFit the smallest one. The scan only
+    // reads headers; merging the run and splitting off the allocation
+    // happen afterwards, with interrupts off, so nothing ever sees a half
+    // merged zone.
+    fn scan(&mut self, size: usize, policy: FitPolicy) -> Option<*mut usize> {
+        // First header and combined size of the best run so far.
+        let mut found: Option<(*mut usize, usize)> = None;
+        // Same for the current run of free chunks.
+        let mut run: Option<(*mut usize, usize)> = None;
+        // Best fit can only judge a run once it has ended.
+        let mut consider = |run: Option<(*mut usize, usize)>| match (run, found) {
+            (Some((_, run_size)), Some((_, best_size))) if run_size >= best_size => {}
+            (Some((_, run_size)), _) if run_size >= size => found = run,
+            _ => {}
+        };
+
+        for (ptr, head) in self.chunks() {
+            if !head.is_free() {
+                consider(run.take());
+                continue;
+            }
+            let (run_ptr, run_size) = match run {
+                Some((run_ptr, run_size)) => (run_ptr, run_size + HEADER_SIZE + head.chunk_size()),
+                None => (ptr, head.chunk_size()),
+            };
+            run = Some((run_ptr, run_size));
+            if policy == FitPolicy::FirstFit && run_size >= size {
+                break;
+            }
+        }
+        consider(run);
+        let (ptr, _) = found?;
+
+        let _guard = InterruptGuard::new();
+        let mut chunks = self.chunks_from(ptr);
+        let (_, mut head) = chunks.next()?;
+        // Take the whole run, so the remainder after the split is never
+        // left next to another free chunk.
+        for (next_ptr, next) in chunks.take_while(|(_, next)| next.is_free()) {
+            head.merge(next, next_ptr);
+        }
+        head.write_to(ptr);
+        alloc_chunk(size, ptr, self, &mut head);
+        Some(ptr.map_addr(|addr| addr + HEADER_SIZE))
+    }
+
+    /// Check that the chunks exactly fill the zone, that the refs count
+    /// matches the used chunks and that no two free chunks are adjacent.
+    fn check_invariants(&self) -> Result<(), &'static str> {
+        let mut used = 0;
+        let mut prev_free = false;
+        let mut end = unsafe { self.base.add(1) };
+        for (ptr, head) in self.chunks() {
+            if head.is_free() {
+                if prev_free {
+                    return Err("adjacent free chunks");
                 }
+                prev_free = true;
             } else {
-                alloc_chunk(size, curr, self, &mut head);
-                return Some(curr.map_addr(|addr| addr + HEADER_SIZE));
+                used += 1;
+                prev_free = false;
             }
+            end = ptr.map_addr(|addr| addr + HEADER_SIZE + head.chunk_size());
+        }
+        if end.addr() != self.base.addr() + PAGE_SIZE {
+            return Err("chunk sizes don't add up to the zone size");
+        }
+        if used != self.get_refs() {
+            return Err("refs count doesn't match the used chunks");
+        }
+        Ok(())
+    }
+
+    // Merge every run of free chunks in this zone into a single chunk,
+    // in one pass over the zone. Chunks are threaded by their sizes, so
+    // growing the first header of a run is all it takes to unlink the
+    // rest. Returns the size of the largest free chunk afterwards.
+    fn defragment(&mut self) -> usize {
+        let mut largest = 0;
+        let mut run: Option<(*mut usize, Header)> = None;
+
+        for (ptr, head) in self.chunks() {
+            if !head.is_free() {
+                run = None;
+                continue;
+            }
+            let (ptr, head) = match run.take() {
+                Some((run_ptr, mut run_head)) => {
+                    run_head.merge(head, ptr);
+                    run_head.write_to(run_ptr);
+                    (run_ptr, run_head)
+                }
+                None => (ptr, head),
+            };
+            largest = core::cmp::max(largest, head.chunk_size());
+            run = Some((ptr, head));
+        }
+        largest
+    }
+}
+
+/// debug_kalloc: panic if the zone holding chunk data `ptr` is
+/// inconsistent. Whole page allocations have no zone.
+fn check_zone(ptr: *mut usize) {
+    if cfg!(feature = "debug_kalloc") && ptr.addr() & PAGE_MASK != 0 {
+        let zone = Zone::from(ptr.map_addr(page_align_down));
+        if let Err(e) = zone.check_invariants() {
+            panic!("Kalloc zone at {:?} is corrupt: {}", zone.base, e);
+        }
+    }
+}
+
+/// Iterator over the chunks of a single zone. Yields each chunk's header
+/// address along with the header read from it.
+struct ChunkIter<'a> {
+    curr: *mut usize, // Next chunk header to read.
+    end: *mut usize,  // End of the zone's page.
+    _zone: PhantomData<&'a Zone>,
+}
+
+impl<'a> Iterator for ChunkIter<'a> {
+    type Item = (*mut usize, Header);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The last chunk may end exactly at the zone boundary, so don't
+        // read a header at `end`, it belongs to the next page.
+        if self.curr >= self.end {
+            return None;
+        }
+        let ptr = self.curr;
+        let head = Header::from(ptr);
+        self.curr = ptr.map_addr(|addr| addr + HEADER_SIZE + head.chunk_size());
+        Some((ptr, head))
+    }
+}
+
+/// Iterator over the zones of a `Kalloc` pool, following each zone's
+/// next pointer. Yields copies of the zone headers.
+struct ZoneIter<'a> {
+    front: *mut usize, // Next zone to yield from the front, null at the end.
+    back: *mut usize,  // Last zone yielded from the back, null if none yet.
+    _pool: PhantomData<&'a Kalloc>,
+}
+
+impl<'a> Iterator for ZoneIter<'a> {
+    type Item = Zone;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        let zone = Zone::from(self.front);
+        self.front = zone
+            .get_next()
+            .map_or(core::ptr::null_mut(), |addr| addr as *mut usize);
+        Some(zone)
+    }
+}
+
+// The zone list is singly linked, so each step from the back walks
+// forward from the front to find the zone before the last one yielded.
+// That costs a traversal per step, but it doesn't need an address stack,
+// which we couldn't allocate from inside the allocator anyway.
+impl<'a> DoubleEndedIterator for ZoneIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        let mut curr = self.front;
+        loop {
+            let zone = Zone::from(curr);
+            let next = zone
+                .get_next()
+                .map_or(core::ptr::null_mut(), |addr| addr as *mut usize);
+            if next == self.back {
+                self.back = curr;
+                return Some(zone);
+            }
+            curr = next;
         }
-        None
+    }
+}
+
+impl KernelAllocator for Kalloc {
+    fn alloc(&mut self, size: usize, align: usize) -> Result<*mut u8, VmError> {
+        // Every chunk is at least HEADER_SIZE aligned.
+        let align = core::cmp::max(align, HEADER_SIZE);
+        Ok(self.alloc_aligned(size, align)?.cast())
+    }
+
+    fn free(&mut self, ptr: *mut u8, _size: usize) {
+        if let Err(e) = Kalloc::free(self, ptr) {
+            panic!("Small free failed {:?}", e)
+        }
+    }
+}
+
+/// Bytes an allocation of `size` actually takes from the pool: rounded
+/// up to 8 bytes, or a whole page if too big for a zone.
+fn alloc_size(size: usize) -> usize {
+    let size = ((size + 7) & !7) + REDZONE_SIZE;
+    if size > MAX_CHUNK_SIZE {
+        PAGE_SIZE
+    } else {
+        size
+    }
+}
+
+/// The part of an allocation taking `footprint` bytes that the caller
+/// can use, i.e. without the red-zone. What the journal records, for
+/// allocs and frees alike.
+fn usable_size(footprint: usize) -> usize {
+    if footprint == PAGE_SIZE {
+        PAGE_SIZE
+    } else {
+        footprint - REDZONE_SIZE
     }
 }
 
 fn alloc_chunk(size: usize, ptr: *mut usize, zone: &mut Zone, head: &mut Header) {
+    check_poison(ptr.map_addr(|addr| addr + HEADER_SIZE));
     zone.increment_refs()
         .expect("Maximum zone allocation limit exceeded.");
     head.set_used();
@@ -331,12 +645,24 @@ fn alloc_chunk(size: usize, ptr: *mut usize, zone: &mut Zone, head: &mut Header)
         let (_, _) = head.split(size, ptr);
         //next.write_to(next_addr);
     }
+    if cfg!(feature = "debug_kalloc") {
+        unsafe {
+            redzone(ptr, size).write(REDZONE);
+        }
+    }
+}
+
+/// debug_kalloc: the red-zone word of the used chunk with header at
+/// `ptr` and size `size`.
+fn redzone(ptr: *mut usize, size: usize) -> *mut usize {
+    ptr.map_addr(|addr| addr + HEADER_SIZE + size - REDZONE_SIZE)
 }
 
 unsafe fn write_zone_header_pair(zone: &Zone, header: &Header) {
     let base = zone.base;
     base.write(zone.next);
     base.add(1).write(header.fields);
+    poison(base.add(2), header.chunk_size());
 }
 
 impl Kalloc {
@@ -346,7 +672,7 @@ impl Kalloc {
     /// chunk header.
     pub fn new(start: Page) -> Self {
         // Make sure start of allocation pool is page aligned.
-        assert_eq!(start.addr.addr() & (PAGE_SIZE - 1), 0);
+        assert_eq!(start.addr.addr() & PAGE_MASK, 0);
         // New page is the first zone in the Kalloc pool.
         let zone = Zone::new(start.addr);
         let head = Header::new(MAX_CHUNK_SIZE);
@@ -355,12 +681,15 @@ impl Kalloc {
         }
         Kalloc {
             head: start.addr,
-            end: start.addr.map_addr(|addr| addr + 0x1000),
+            end: start.addr.map_addr(|addr| addr + PAGE_SIZE),
+            used_bytes: 0,
+            high_water_used_bytes: 0,
+            fit_policy: FitPolicy::FirstFit,
         }
     }
 
-    fn grow_pool(&self, tail: &mut Zone) -> Result<(Zone, Header), VmError> {
-        let page = palloc()?;
+    // Link `page` in after `tail` as a new, empty zone.
+    fn grow_pool(&self, tail: &mut Zone, page: Page) -> (Zone, Header) {
         unsafe {
             tail.write_next(page.addr);
         }
@@ -369,32 +698,31 @@ impl Kalloc {
         unsafe {
             write_zone_header_pair(&zone, &head);
         }
-        Ok((zone, head))
+        (zone, head)
     }
 
     fn shrink_pool(&self, mut drop_zone: Zone) {
         if drop_zone.base != self.head {
-            let mut curr_ptr = self.head;
-            //let mut curr_zone = Zone::from(curr_ptr);
-
-            loop {
-                let curr_zone = Zone::from(curr_ptr);
-
-                if let Some(next_zone) = curr_zone.next_zone() {
-                    if drop_zone.base == next_zone.base {
-                        drop_zone.free_self(curr_zone);
-                        return;
-                    } else {
-                        curr_ptr = next_zone.base;
-                    }
-                } else {
-                    break;
-                }
+            let drop_addr = drop_zone.base.addr();
+            match self
+                .zones()
+                .find(|zone| zone.get_next().ok() == Some(drop_addr))
+            {
+                Some(prev_zone) => drop_zone.free_self(prev_zone),
+                None => panic!(
+                    "Tried to free zone at: {:?}. Not in the pool...",
+                    drop_zone.base
+                ),
             }
-            panic!(
-                "Tried to free zone after: {:?}. Not in the pool...",
-                curr_ptr
-            );
+        }
+    }
+
+    /// Iterate over the zones in this pool, starting at the head zone.
+    fn zones(&self) -> ZoneIter<'_> {
+        ZoneIter {
+            front: self.head,
+            back: core::ptr::null_mut(),
+            _pool: PhantomData,
         }
     }
 
@@ -403,56 +731,216 @@ impl Kalloc {
     /// 2a. If success: Return chunk's starting address (*mut usize).
     /// 2b. Else, move to next zone and go back to step 1.
     /// 3. If no zone had a fit, then try to allocate a new zone (palloc()).
-    /// 4. If 3. success, allocate from first chunk in new page. Else, fail with OOM.
+    /// 4. If 3. success, allocate from first chunk in new page. Else, ask the
+    ///    registered memory pressure handlers to reclaim memory and retry.
+    ///
+    /// Sizes too big for a zone but no bigger than a page skip the zones
+    /// and are given a whole page.
     pub fn alloc(&mut self, size: usize) -> Result<*mut usize, KallocError> {
+        let ptr = match self.try_alloc(size) {
+            Err(KallocError::OOM) => {
+                relieve_pressure(|| self.try_alloc(size).ok()).ok_or(KallocError::OOM)
+            }
+            res => res,
+        }?;
+        self.account_alloc(ptr, size);
+        Ok(ptr)
+    }
+
+    fn account_alloc(&mut self, ptr: *mut usize, size: usize) {
+        check_zone(ptr);
+        record(AllocOp::Alloc, ptr, usable_size(alloc_size(size)));
+        self.used_bytes += alloc_size(size);
+        if self.used_bytes > self.high_water_used_bytes {
+            self.high_water_used_bytes = self.used_bytes;
+        }
+    }
+
+    /// Like `alloc`, but the memory comes from a zone whose page lies in
+    /// physical memory [phys_start, phys_end), e.g. for DMA. If no zone
+    /// in range has room, a new one is taken from pages in range. Fails
+    /// with OOM once the range is exhausted.
+    pub fn alloc_from(
+        &mut self,
+        size: usize,
+        phys_start: usize,
+        phys_end: usize,
+    ) -> Result<*mut usize, KallocError> {
+        if size == 0 {
+            return Err(KallocError::Void);
+        }
+        let rounded = (size + 7) & !7;
+        if rounded > PAGE_SIZE {
+            return Err(KallocError::SizeTooLarge(size));
+        }
+        let rounded = rounded + REDZONE_SIZE;
+        let (bottom, top) = (PhysAddr::new(phys_start), PhysAddr::new(phys_end));
+
+        let ptr = if rounded > MAX_CHUNK_SIZE {
+            // Page sized chunk, no zone or header.
+            palloc_range(bottom, top)?.addr
+        } else {
+            self.try_alloc_from(rounded, bottom, top)?
+        };
+        self.account_alloc(ptr, size);
+        Ok(ptr)
+    }
+
+    fn try_alloc_from(
+        &mut self,
+        size: usize,
+        bottom: PhysAddr,
+        top: PhysAddr,
+    ) -> Result<*mut usize, KallocError> {
+        let in_range = |zone: &Zone| (bottom.addr()..top.addr()).contains(&zone.base.addr());
+        for mut zone in self.zones().filter(in_range) {
+            if zone.defragment() >= size {
+                if let Some(ptr) = zone.scan(size, self.fit_policy) {
+                    return Ok(ptr);
+                }
+            }
+        }
+
+        let page = palloc_range(bottom, top)?;
+        let mut tail = self.zones().last().expect("Kalloc pool has no zones.");
+        let (mut zone, mut head) = self.grow_pool(&mut tail, page);
+        let head_ptr = zone.base.map_addr(|addr| addr + ZONE_SIZE);
+        alloc_chunk(size, head_ptr, &mut zone, &mut head);
+        Ok(head_ptr.map_addr(|addr| addr + HEADER_SIZE))
+    }
+
+    /// Peak number of bytes allocated at once.
+    pub fn watermark(&self) -> usize {
+        self.high_water_used_bytes
+    }
+
+    /// Restart the high-water mark from the current usage, to measure
+    /// the peak over a window of time.
+    pub fn reset_watermark(&mut self) {
+        self.high_water_used_bytes = self.used_bytes;
+    }
+
+    pub fn stats(&self) -> KallocStats {
+        let (mut free_bytes, mut largest_free_chunk) = (0, 0);
+        for zone in self.zones() {
+            for (_, head) in zone.chunks().filter(|(_, head)| head.is_free()) {
+                free_bytes += head.chunk_size();
+                largest_free_chunk = core::cmp::max(largest_free_chunk, head.chunk_size());
+            }
+        }
+        KallocStats {
+            used_bytes: self.used_bytes,
+            high_water_used_bytes: self.high_water_used_bytes,
+            free_bytes,
+            largest_free_chunk,
+        }
+    }
+
+    /// Change how zones pick a chunk for future allocations.
+    pub fn set_fit_policy(&mut self, policy: FitPolicy) {
+        self.fit_policy = policy;
+    }
+
+    /// Like `alloc`, but the returned address is a multiple of `align`,
+    /// which must be a power of two of at least HEADER_SIZE. Chunks only
+    /// guarantee HEADER_SIZE alignment, so anything stricter gets a whole
+    /// page.
+    pub fn alloc_aligned(&mut self, size: usize, align: usize) -> Result<*mut usize, KallocError> {
+        if !align.is_power_of_two() || !(HEADER_SIZE..=PAGE_SIZE).contains(&align) {
+            return Err(KallocError::AlignmentError(align));
+        }
+        if size == 0 {
+            return Err(KallocError::Void);
+        }
+        if align == HEADER_SIZE {
+            return self.alloc(size);
+        }
+        if size > PAGE_SIZE {
+            return Err(KallocError::SizeTooLarge(size));
+        }
+        // Page sized requests get a whole (page aligned) page.
+        self.alloc(PAGE_SIZE)
+    }
+
+    fn try_alloc(&mut self, size: usize) -> Result<*mut usize, KallocError> {
         if size == 0 {
             return Err(KallocError::Void);
         }
         // Round to a 8 byte granularity
         let size = if size % 8 != 0 { (size + 7) & !7 } else { size };
+        if size > PAGE_SIZE {
+            return Err(KallocError::SizeTooLarge(size));
+        }
+        let size = size + REDZONE_SIZE;
+        if size > MAX_CHUNK_SIZE {
+            // Page sized chunk, no zone or header.
+            return Ok(palloc()?.addr);
+        }
 
-        let curr = self.head;
-        let end = self.end.map_addr(|addr| addr - 0x1000);
-        let mut zone = Zone::from(curr);
-        let mut trail = zone;
-
-        while zone.base <= end {
-            if let Some(ptr) = zone.scan(size) {
+        let mut tail = None;
+        for mut zone in self.zones() {
+            if let Some(ptr) = zone.scan(size, self.fit_policy) {
                 return Ok(ptr);
-            } else {
-                zone = match zone.next_zone() {
-                    Some(zone) => zone,
-                    None => {
-                        if let Ok((mut zone, mut head)) = self.grow_pool(&mut trail) {
-                            let head_ptr = zone.base.map_addr(|addr| addr + ZONE_SIZE);
-                            alloc_chunk(size, head_ptr, &mut zone, &mut head);
-                            return Ok(head_ptr.map_addr(|addr| addr + HEADER_SIZE));
-                        } else {
-                            return Err(KallocError::OOM);
-                        }
-                    }
+            }
+            tail = Some(zone);
+        }
+
+        // Merging free runs may turn up a big enough chunk before we
+        // resort to taking another page.
+        for mut zone in self.zones() {
+            if zone.defragment() >= size {
+                if let Some(ptr) = zone.scan(size, self.fit_policy) {
+                    return Ok(ptr);
                 }
-            };
+            }
         }
-        Err(KallocError::OOM)
+
+        // Every zone is full, so grow the pool from the last one.
+        let mut tail = tail.expect("Kalloc pool has no zones.");
+        let (mut zone, mut head) = self.grow_pool(&mut tail, palloc()?);
+        let head_ptr = zone.base.map_addr(|addr| addr + ZONE_SIZE);
+        alloc_chunk(size, head_ptr, &mut zone, &mut head);
+        Ok(head_ptr.map_addr(|addr| addr + HEADER_SIZE))
     }
 
     /// 1. Calculate the header offset from the data pointer.
     /// 2. Calculate the zone offset from the data pointer.
     /// 3. Check if zone refs count is 0, if so, release zone.
     /// 4. If zone refs count != 0, try to merge this freed chunk.
-    pub fn free<T>(&mut self, ptr: *mut T) {
+    ///
+    /// Chunk data always follows a zone and chunk header, so a page
+    /// aligned `ptr` must be a whole page allocation and goes back to pfree.
+    ///
+    /// With debug_kalloc, fails with HeapOverflow if the chunk's red-zone
+    /// was overwritten.
+    pub fn free<T>(&mut self, ptr: *mut T) -> Result<(), KallocError> {
         let ptr: *mut usize = ptr.cast();
+        if ptr.addr() & PAGE_MASK == 0 {
+            record(AllocOp::Free, ptr, PAGE_SIZE);
+            let _ = pfree(Page::from(ptr));
+            self.used_bytes -= PAGE_SIZE;
+            return Ok(());
+        }
         // Assume that round down to nearest page is the current zone base addr.
-        let mut zone = Zone::from(ptr.map_addr(|addr| addr & !(PAGE_SIZE - 1)));
+        let mut zone = Zone::from(ptr.map_addr(page_align_down));
         let head_ptr = ptr.map_addr(|addr| addr - HEADER_SIZE);
         let mut head = Header::from(head_ptr);
         assert!(!head.is_free(), "Kalloc double free.");
+        if cfg!(feature = "debug_kalloc")
+            && unsafe { redzone(head_ptr, head.chunk_size()).read() } != REDZONE
+        {
+            return Err(KallocError::HeapOverflow(ptr));
+        }
+        // Only frees that happen go in the journal.
+        record(AllocOp::Free, ptr, usable_size(head.chunk_size()));
+        poison(ptr, head.chunk_size());
         head.set_unused();
+        self.used_bytes -= head.chunk_size();
 
         let mut chunk_merge_flag = false;
         if let Ok(count) = zone.decrement_refs() {
-            if count == 0 {
+            // The head zone is never released, so it still needs merging.
+            if count == 0 && zone.base != self.head {
                 // this is costly, as it's a list traversal
                 self.shrink_pool(zone);
             } else {
@@ -462,15 +950,27 @@ impl Kalloc {
             panic!("Negative zone refs count: {}", zone.get_refs())
         }
 
-        if chunk_merge_flag {
-            let next_ptr = ptr.map_addr(|addr| addr + head.chunk_size());
+        if !chunk_merge_flag {
+            return Ok(());
+        }
+        let next_ptr = ptr.map_addr(|addr| addr + head.chunk_size());
+        if next_ptr < zone.base.map_addr(|addr| addr + PAGE_SIZE) {
             let next = Header::from(next_ptr);
-            if next.is_free() && next_ptr < zone.base.map_addr(|addr| addr + 0x1000) {
+            if next.is_free() {
                 // back to back free, merge
-                //head.set_size(head.chunk_size() + HEADER_SIZE + next.chunk_size())
                 head.merge(next, next_ptr);
             }
         }
-        head.write_to(head_ptr);
+        // Chunks don't link back, so find the previous one from the start
+        // of the zone. If it's free, this chunk merges into it.
+        match zone.chunks().take_while(|(p, _)| *p < head_ptr).last() {
+            Some((prev_ptr, mut prev)) if prev.is_free() => {
+                prev.merge(head, head_ptr);
+                prev.write_to(prev_ptr);
+            }
+            _ => head.write_to(head_ptr),
+        }
+        check_zone(ptr);
+        Ok(())
     }
 }